@@ -1,5 +1,5 @@
-use chrono::{DateTime, Utc};
-use clap::{Parser, Subcommand};
+use chrono::{DateTime, Datelike, Days, Local, Months, NaiveDate, Utc, Weekday};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use crossterm::{
     event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
     execute,
@@ -13,20 +13,40 @@ use ratatui::{
     text::{Line, Span},
     widgets::{Block, Borders, Paragraph, Widget},
 };
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::io::{self, stdout};
-use std::path::PathBuf;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write, stdout};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// `println!` for command output that exits quietly once stdout is closed (e.g.
+/// when piped into `head`) instead of panicking
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        if let Err(e) = writeln!(stdout(), $($arg)*) {
+            output_failed(e)
+        }
+    };
+}
 
 #[derive(Parser)]
 #[command(name = "backlog")]
-#[command(version)]
+#[command(version, disable_version_flag = true)]
 #[command(about = "A simple backlog manager for your repos", long_about = None)]
 struct Cli {
     /// Print version
     #[arg(short = 'v', long = "version", action = clap::ArgAction::Version)]
     version: (),
 
+    /// Output format for scripts: plain text, a JSON document, or one JSON record per line
+    #[arg(long, global = true, value_enum, default_value_t = Format::Plain)]
+    format: Format,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -35,8 +55,16 @@ struct Cli {
 enum Commands {
     /// Add a new item to the backlog
     Add {
+        /// Priority of the new item
+        #[arg(short, long, value_enum)]
+        priority: Option<Priority>,
+        /// Due date: YYYY-MM-DD, today, tomorrow, a weekday, or +3d / +2w / +1m
+        #[arg(short, long, value_parser = parse_due_arg)]
+        due: Option<NaiveDate>,
+        /// Add as a subtask of this item (number, or @id)
+        #[arg(short, long)]
+        under: Option<ItemRef>,
         /// The backlog item description
-        #[arg(trailing_var_arg = true)]
         description: Vec<String>,
     },
     /// List backlog items (current repo or all)
@@ -44,580 +72,4513 @@ enum Commands {
         /// Show all backlogs across all repos
         #[arg(short, long)]
         all: bool,
+        /// Order items by this key instead of their position
+        #[arg(short, long, value_enum, default_value_t = SortKey::Position)]
+        sort: SortKey,
+        /// Only show items with this tag (repeat to require several)
+        #[arg(short, long = "tag")]
+        tags: Vec<String>,
+        /// Show archived items instead
+        #[arg(long, conflicts_with = "all")]
+        archived: bool,
     },
-    /// Mark an item as done
+    /// Mark items as done
     Done {
-        /// Item number to mark as done
-        number: usize,
+        /// Item numbers, @ids, or ranges like 3-6
+        #[arg(required_unless_present = "pattern")]
+        items: Vec<ItemSpec>,
+        /// Also mark every pending item whose description contains this text
+        #[arg(short, long = "match", value_name = "TEXT")]
+        pattern: Option<String>,
+    },
+    /// Mark a done item as not done again
+    Undone {
+        /// Item number, or @id
+        item: ItemRef,
     },
-    /// Remove an item from the backlog
+    /// Remove items (and their subtasks) from the backlog
     Remove {
-        /// Item number to remove
-        number: usize,
+        /// Item numbers, @ids, or ranges like 3-6
+        #[arg(required_unless_present_any = ["pattern", "done"])]
+        items: Vec<ItemSpec>,
+        /// Also remove every item whose description contains this text
+        #[arg(short, long = "match", value_name = "TEXT")]
+        pattern: Option<String>,
+        /// Also remove every completed item (unless it has pending subtasks)
+        #[arg(long)]
+        done: bool,
+    },
+    /// Change an item's description (opens $EDITOR when no text is given)
+    Edit {
+        /// Item number, or @id
+        item: ItemRef,
+        /// The new description; #tags in it are added to the item
+        text: Vec<String>,
+    },
+    /// Move an item (with its subtasks) to another position
+    Move {
+        /// Item number, or @id
+        item: ItemRef,
+        /// Target position; subtasks stay within their parent
+        to: usize,
+    },
+    /// Add tags to an item
+    Tag {
+        /// Item number, or @id
+        item: ItemRef,
+        /// Tags to add, with or without a leading #
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from an item
+    Untag {
+        /// Item number, or @id
+        item: ItemRef,
+        /// Tags to remove, with or without a leading #
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Mark an item as blocked until other items are done
+    Block {
+        /// Item number, or @id, that is blocked
+        item: ItemRef,
+        /// Item(s) it has to wait for
+        #[arg(long, required = true, num_args = 1..)]
+        on: Vec<ItemRef>,
+    },
+    /// Remove blockers from an item (all of them unless --on is given)
+    Unblock {
+        /// Item number, or @id
+        item: ItemRef,
+        /// Only remove these blockers
+        #[arg(long, num_args = 1..)]
+        on: Vec<ItemRef>,
+    },
+    /// Show an item with all its details and notes
+    Show {
+        /// Item number, or @id
+        item: ItemRef,
+    },
+    /// Edit an item's notes in $EDITOR
+    Note {
+        /// Item number, or @id
+        item: ItemRef,
+    },
+    /// List pending items with due dates across all repos, or set an item's due date
+    Due {
+        /// Item number, or @id, whose due date to set
+        #[arg(requires = "date")]
+        item: Option<ItemRef>,
+        /// New due date (same formats as `add --due`), or "none" to clear it
+        date: Option<String>,
+    },
+    /// Set or clear an item's priority
+    Priority {
+        /// Item number, or @id
+        item: ItemRef,
+        /// New priority, or "none" to clear it
+        #[arg(value_enum)]
+        level: PriorityArg,
     },
-    /// Show what to do next (first incomplete item)
+    /// Find items whose description or notes contain some text (ignoring case)
+    Search {
+        /// Text to look for
+        query: String,
+        /// Search the backlogs of all repos
+        #[arg(short, long)]
+        all: bool,
+        /// Treat the query as a regular expression
+        #[arg(short = 'e', long)]
+        regex: bool,
+    },
+    /// Show what to do next (highest-priority incomplete item)
     Next,
+    /// Move completed items (with their finished subtasks) to .backlog/archive.json
+    Archive {
+        /// Only archive items completed longer ago than this, e.g. 14d, 2w or 1m
+        #[arg(long, value_name = "AGE", value_parser = parse_age_arg)]
+        older_than: Option<DateTime<Utc>>,
+    },
+    /// Bring an archived item (and its archived subtasks) back into the backlog
+    Restore {
+        /// Item number as shown by `list --archived`, or @id
+        item: ItemRef,
+    },
+    /// Import TODO, FIXME and HACK comments from the code as items
+    Scan {
+        /// Import without asking
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Set an item's status, e.g. `backlog status 3 doing`
+    Status {
+        /// Item number, or @id
+        item: ItemRef,
+        /// One of the repo's statuses (todo, doing and done unless configured)
+        status: String,
+    },
+    /// Create and check out a git branch for an item and mark it as started
+    Start {
+        /// Item number, or @id
+        item: ItemRef,
+    },
+    /// Close items from commit messages such as "Fixes backlog#12"
+    Hook {
+        #[command(subcommand)]
+        action: HookAction,
+    },
+    /// Revert the last change made with backlog
+    Undo {
+        /// Undo even if the backlog was changed some other way since (e.g. by git)
+        #[arg(long)]
+        force: bool,
+    },
+    /// Reapply the last undone change
+    Redo {
+        /// Redo even if the backlog was changed some other way since
+        #[arg(long)]
+        force: bool,
+    },
+    /// Bring this repo's backlog files up to the current format, optionally
+    /// converting between one backlog.json and one file per item
+    Migrate {
+        /// Storage layout to convert to (keeps the current one if left out)
+        #[arg(value_enum)]
+        to: Option<Storage>,
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Merge two versions of backlog.json item by item (run by git as a merge driver)
+    MergeDriver {
+        /// Common ancestor (%O)
+        base: PathBuf,
+        /// Our version, overwritten with the result (%A)
+        ours: PathBuf,
+        /// Their version (%B)
+        theirs: PathBuf,
+    },
+    /// Set up git to merge backlog.json with 'backlog merge-driver'
+    InstallMergeDriver,
     /// Interactive CLI mode
-    Cli,
+    Cli {
+        /// Show the backlogs of all repos, with a sidebar to switch between them
+        #[arg(short, long)]
+        all: bool,
+    },
+    /// Check backlog.json and archive.json and recover items from a corrupt or conflicted file
+    #[command(alias = "repair")]
+    Doctor {
+        /// Write the recovered items back (a copy of the broken file is kept as a .bak)
+        #[arg(long)]
+        fix: bool,
+    },
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct BacklogItem {
-    description: String,
-    created_at: DateTime<Utc>,
-    done: bool,
+#[derive(Subcommand)]
+enum HookAction {
+    /// Install commit-msg and post-commit hooks in this repo
+    Install,
+    /// Entry point for the installed hooks
+    Run {
+        hook: HookName,
+        /// Arguments git passed to the hook
+        args: Vec<String>,
+    },
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct Backlog {
-    items: Vec<BacklogItem>,
+/// The git hooks `backlog hook install` sets up
+#[derive(Clone, Copy, ValueEnum)]
+enum HookName {
+    /// Rejects commits that reference items that don't exist
+    CommitMsg,
+    /// Marks referenced items done and records the commit on them
+    PostCommit,
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct GlobalIndex {
-    /// Maps repo paths to their backlog file paths
-    repos: Vec<String>,
+impl HookName {
+    fn file_name(self) -> &'static str {
+        match self {
+            HookName::CommitMsg => "commit-msg",
+            HookName::PostCommit => "post-commit",
+        }
+    }
 }
 
-fn get_repo_root() -> Option<PathBuf> {
-    let current_dir = std::env::current_dir().ok()?;
-    let mut dir = current_dir.as_path();
+/// Identifies an item on the command line, either by its 1-based position or
+/// by its stable ID written as `@id`
+#[derive(Clone, Copy)]
+enum ItemRef {
+    Position(usize),
+    Id(u64),
+}
 
-    loop {
-        if dir.join(".git").exists() {
-            return Some(dir.to_path_buf());
+impl FromStr for ItemRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(id) = s.strip_prefix('@') {
+            id.parse()
+                .map(ItemRef::Id)
+                .map_err(|_| format!("invalid item id: {}", s))
+        } else {
+            s.parse()
+                .map(ItemRef::Position)
+                .map_err(|_| format!("expected an item number or @id, got: {}", s))
         }
-        dir = dir.parent()?;
     }
 }
 
-fn get_repo_backlog_path() -> Option<PathBuf> {
-    let repo_root = get_repo_root()?;
-    let new_dir = repo_root.join(".backlog");
-    let old_dir = repo_root.join(".todo");
-
-    // Migrate .todo to .backlog if old exists and new doesn't
-    if old_dir.exists() && !new_dir.exists() && fs::rename(&old_dir, &new_dir).is_err() {
-        // If rename fails, fall back to old path
-        return Some(old_dir.join("backlog.json"));
+impl fmt::Display for ItemRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemRef::Position(n) => write!(f, "{}", n),
+            ItemRef::Id(id) => write!(f, "@{}", id),
+        }
     }
+}
 
-    Some(new_dir.join("backlog.json"))
+/// Selects items on the command line: a single `ItemRef`, or an inclusive
+/// range of positions written as `3-6`
+#[derive(Clone, Copy)]
+enum ItemSpec {
+    One(ItemRef),
+    Range(usize, usize),
 }
 
-fn get_global_dir() -> PathBuf {
-    dirs::home_dir()
-        .expect("Could not find home directory")
-        .join(".backlog")
+impl FromStr for ItemSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((start, end)) = s.split_once('-') else {
+            return s.parse().map(ItemSpec::One);
+        };
+        match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) if start <= end => Ok(ItemSpec::Range(start, end)),
+            _ => Err(format!("invalid range: {}", s)),
+        }
+    }
 }
 
-fn get_global_index_path() -> PathBuf {
-    get_global_dir().join("index.json")
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Priority {
+    #[value(alias = "h")]
+    High,
+    #[value(alias = "m", alias = "med")]
+    Medium,
+    #[value(alias = "l")]
+    Low,
 }
 
-fn load_backlog(path: &PathBuf) -> Backlog {
-    if path.exists() {
-        let content = fs::read_to_string(path).unwrap_or_default();
-        serde_json::from_str(&content).unwrap_or_default()
-    } else {
-        Backlog::default()
+impl Priority {
+    fn label(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Medium => "med",
+            Priority::Low => "low",
+        }
     }
-}
 
-fn save_backlog(path: &PathBuf, backlog: &Backlog) -> std::io::Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+    fn color(self) -> Color {
+        match self {
+            Priority::High => Color::Red,
+            Priority::Medium => Color::Yellow,
+            Priority::Low => Color::Blue,
+        }
     }
-    let content = serde_json::to_string_pretty(backlog)?;
-    fs::write(path, content)
-}
 
-fn load_global_index() -> GlobalIndex {
-    let path = get_global_index_path();
-    if path.exists() {
-        let content = fs::read_to_string(&path).unwrap_or_default();
-        serde_json::from_str(&content).unwrap_or_default()
-    } else {
-        GlobalIndex::default()
+    /// Next level when cycling with the TUI key: high, medium, low, unset
+    fn cycle(priority: Option<Priority>) -> Option<Priority> {
+        match priority {
+            None => Some(Priority::High),
+            Some(Priority::High) => Some(Priority::Medium),
+            Some(Priority::Medium) => Some(Priority::Low),
+            Some(Priority::Low) => None,
+        }
     }
 }
 
-fn save_global_index(index: &GlobalIndex) -> std::io::Result<()> {
-    let path = get_global_index_path();
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+/// Priority as given to `backlog priority`, where "none" clears it
+#[derive(Clone, Copy, ValueEnum)]
+enum PriorityArg {
+    #[value(alias = "h")]
+    High,
+    #[value(alias = "m", alias = "med")]
+    Medium,
+    #[value(alias = "l")]
+    Low,
+    None,
+}
+
+impl From<PriorityArg> for Option<Priority> {
+    fn from(arg: PriorityArg) -> Self {
+        match arg {
+            PriorityArg::High => Some(Priority::High),
+            PriorityArg::Medium => Some(Priority::Medium),
+            PriorityArg::Low => Some(Priority::Low),
+            PriorityArg::None => None,
+        }
     }
-    let content = serde_json::to_string_pretty(index)?;
-    fs::write(path, content)
 }
 
-fn register_repo(repo_path: &str) {
-    let mut index = load_global_index();
-    if !index.repos.contains(&repo_path.to_string()) {
-        index.repos.push(repo_path.to_string());
-        let _ = save_global_index(&index);
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum SortKey {
+    /// Backlog order
+    Position,
+    /// Highest priority first
+    Priority,
+    /// Earliest due date first, undated items last
+    Due,
+    /// Oldest first
+    Created,
+    /// Most recently changed first
+    Updated,
+    /// Most recently completed first, pending items last
+    Completed,
+}
+
+#[derive(Clone, Copy, PartialEq, Default, ValueEnum)]
+enum Format {
+    /// Human-readable text
+    #[default]
+    Plain,
+    /// One JSON document per command
+    Json,
+    /// One JSON record per line
+    Ndjson,
+}
+
+/// Status of new items, and the first column of every workflow
+const TODO: &str = "todo";
+/// Status `backlog start` sets
+const DOING: &str = "doing";
+/// Status of finished items, and the last column of every workflow
+const DONE: &str = "done";
+
+/// Character shown between the brackets in front of an item with `status`;
+/// custom statuses use their first letter
+fn status_glyph(status: &str) -> char {
+    match status {
+        TODO => ' ',
+        DOING => '~',
+        DONE => 'x',
+        other => other.chars().next().unwrap_or('?'),
     }
 }
 
-#[derive(PartialEq)]
-enum Mode {
-    Normal,
-    Edit,
-    Add,
-    ConfirmDelete,
+#[derive(Serialize, Deserialize, Clone)]
+struct BacklogItem {
+    /// Stable identifier, unique within the backlog and never reused
+    #[serde(default)]
+    id: u64,
+    description: String,
+    created_at: DateTime<Utc>,
+    /// Where the item is in the repo's workflow, e.g. "todo", "doing" or "done"
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<Priority>,
+    /// Lowercase labels without the leading `#`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due: Option<NaiveDate>,
+    /// Free-form markdown body: repro steps, acceptance criteria, ...
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notes: Option<String>,
+    /// ID of the item this is a subtask of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<u64>,
+    /// IDs of items that have to be done before this one can start
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    blocked_by: Vec<u64>,
+    /// When the item was last marked done; cleared when it's reopened
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    completed_at: Option<DateTime<Utc>>,
+    /// When the item was last changed (missing in files from older versions)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<DateTime<Utc>>,
+    /// Code comment the item was imported from by `backlog scan`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Source>,
+    /// SHA of the commit whose message closed the item ("Fixes backlog#12")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    closed_by: Option<String>,
+    /// When work on the item began with `backlog start`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    started_at: Option<DateTime<Utc>>,
+    /// Git branch `backlog start` created for the item
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    branch: Option<String>,
 }
 
-struct App {
-    backlog: Backlog,
-    backlog_path: PathBuf,
-    selected: usize,
-    scroll_offset: usize,
-    mode: Mode,
-    edit_buffer: String,
-    edit_cursor: usize,
-    output: Option<String>,
-    pending_d: bool,      // for dd delete
-    hide_completed: bool, // toggle to hide completed items
+/// Where in the code an item imported by `backlog scan` came from
+#[derive(Serialize, Deserialize, Clone)]
+struct Source {
+    /// Path relative to the repo root
+    file: String,
+    line: usize,
+    /// The comment's text after the marker, which identifies it on later scans
+    text: String,
+    /// Set once a scan no longer finds the comment
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    missing: bool,
 }
 
-impl App {
-    fn new(backlog: Backlog, backlog_path: PathBuf) -> Self {
-        Self {
-            backlog,
-            backlog_path,
-            selected: 0,
-            scroll_offset: 0,
-            mode: Mode::Normal,
-            edit_buffer: String::new(),
-            edit_cursor: 0,
-            output: None,
-            pending_d: false,
-            hide_completed: false,
+impl BacklogItem {
+    /// Records that the item was just modified
+    fn touch(&mut self) {
+        self.updated_at = Some(Utc::now());
+    }
+
+    fn is_done(&self) -> bool {
+        self.status == DONE
+    }
+
+    /// Imported by `backlog scan` from a comment that a later scan no longer found
+    fn comment_gone(&self) -> bool {
+        self.source.as_ref().is_some_and(|source| source.missing)
+    }
+
+    /// Moves the item to another status, keeping `completed_at` and
+    /// `started_at` in step
+    fn set_status(&mut self, status: &str) {
+        if self.status != status {
+            let done = status == DONE;
+            if self.is_done() != done {
+                self.completed_at = done.then(Utc::now);
+                if !done {
+                    self.closed_by = None;
+                }
+            }
+            if status != TODO && !done && self.started_at.is_none() {
+                self.started_at = Some(Utc::now());
+            }
+            self.status = status.to_string();
         }
+        self.touch();
     }
 
-    /// Returns indices of visible items based on hide_completed setting
-    fn visible_indices(&self) -> Vec<usize> {
-        self.backlog
-            .items
-            .iter()
-            .enumerate()
-            .filter(|(_, item)| !self.hide_completed || !item.done)
-            .map(|(i, _)| i)
-            .collect()
+    /// Marks the item done, or back to todo
+    fn set_done(&mut self, done: bool) {
+        self.set_status(if done { DONE } else { TODO });
     }
 
-    /// Converts a visible index to the actual backlog index
-    fn visible_to_actual(&self, visible_idx: usize) -> Option<usize> {
-        self.visible_indices().get(visible_idx).copied()
+    /// Last modification time, falling back to creation for older files
+    fn last_updated(&self) -> DateTime<Utc> {
+        self.updated_at.unwrap_or(self.created_at)
+    }
+    /// Work on it has started (any status between todo and done)
+    fn in_progress(&self) -> bool {
+        self.status != TODO && !self.is_done()
     }
 
-    /// Converts an actual backlog index to a visible index
-    fn actual_to_visible(&self, actual_idx: usize) -> Option<usize> {
-        self.visible_indices().iter().position(|&i| i == actual_idx)
+    /// Status box shown in front of the item in lists, e.g. `[~]`
+    fn checkbox(&self) -> String {
+        format!("[{}]", status_glyph(&self.status))
     }
 
-    fn toggle_hide_completed(&mut self) {
-        self.hide_completed = !self.hide_completed;
-        // Adjust selection if current selection is no longer visible
-        let visible = self.visible_indices();
-        if visible.is_empty() {
-            self.selected = 0;
-        } else if self.selected >= visible.len() {
-            self.selected = visible.len() - 1;
-        }
+    /// Pending and past its due date (as of the local date)
+    fn is_overdue(&self) -> bool {
+        !self.is_done() && self.due.is_some_and(|due| due < Local::now().date_naive())
     }
 
-    fn save(&self) -> io::Result<()> {
-        save_backlog(&self.backlog_path, &self.backlog)
+    fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
     }
 
-    fn move_up(&mut self) {
-        if self.selected > 0 {
-            self.selected -= 1;
+    /// Whether the description or the notes match a search pattern
+    fn matches(&self, pattern: &Regex) -> bool {
+        pattern.is_match(&self.description)
+            || self.notes.as_deref().is_some_and(|n| pattern.is_match(n))
+    }
+
+    /// Adds tags that aren't already present, keeping their order
+    fn add_tags(&mut self, tags: impl IntoIterator<Item = String>) {
+        for tag in tags {
+            if !self.has_tag(&tag) {
+                self.tags.push(tag);
+            }
         }
     }
 
-    fn move_down(&mut self) {
-        let visible_count = self.visible_indices().len();
-        if visible_count > 0 && self.selected < visible_count - 1 {
-            self.selected += 1;
+    /// Sort rank for priority ordering; items without a priority rank as medium
+    fn priority_rank(&self) -> u8 {
+        match self.priority {
+            Some(Priority::High) => 0,
+            Some(Priority::Medium) | None => 1,
+            Some(Priority::Low) => 2,
         }
     }
+}
 
-    fn toggle_done(&mut self) {
-        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
-            self.backlog.items[actual_idx].done = !self.backlog.items[actual_idx].done;
-            let _ = self.save();
-            // If we just completed an item and hide_completed is on, adjust selection
-            if self.hide_completed && self.backlog.items[actual_idx].done {
-                let visible = self.visible_indices();
-                if visible.is_empty() {
-                    self.selected = 0;
-                } else if self.selected >= visible.len() {
-                    self.selected = visible.len() - 1;
-                }
+#[derive(Serialize, Deserialize, Clone)]
+struct Backlog {
+    /// File format version, see `BACKLOG_UPGRADES` (missing in files from before versioning)
+    #[serde(default)]
+    version: u32,
+    items: Vec<BacklogItem>,
+    /// Next ID to hand out; only ever grows so deleted IDs are not reused
+    #[serde(default)]
+    next_id: u64,
+}
+
+impl Default for Backlog {
+    fn default() -> Self {
+        Backlog {
+            version: BACKLOG_VERSION,
+            items: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl Backlog {
+    fn allocate_id(&mut self) -> u64 {
+        let id = self.next_id.max(1);
+        self.next_id = id + 1;
+        id
+    }
+
+    /// Gives an ID to every item that lacks one (files written before IDs existed)
+    fn assign_missing_ids(&mut self) {
+        let max_id = self.items.iter().map(|i| i.id).max().unwrap_or(0);
+        if self.next_id <= max_id {
+            self.next_id = max_id + 1;
+        }
+        for i in 0..self.items.len() {
+            if self.items[i].id == 0 {
+                self.items[i].id = self.allocate_id();
             }
         }
     }
 
-    fn move_item_up(&mut self) {
-        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
-            if actual_idx > 0 {
-                self.backlog.items.swap(actual_idx, actual_idx - 1);
-                // Update selection to follow the item
-                if let Some(new_visible_idx) = self.actual_to_visible(actual_idx - 1) {
-                    self.selected = new_visible_idx;
-                }
-                let _ = self.save();
+    /// The first ID more than one item has, if any
+    fn duplicate_id(&self) -> Option<u64> {
+        let mut seen = HashSet::new();
+        self.items.iter().map(|i| i.id).find(|id| !seen.insert(*id))
+    }
+
+    /// Gives a fresh ID to every item whose ID an earlier item already has,
+    /// which happens when two branches each added items and got merged
+    fn renumber_duplicates(&mut self) {
+        self.assign_missing_ids();
+        let mut seen = HashSet::new();
+        for i in 0..self.items.len() {
+            if !seen.insert(self.items[i].id) {
+                self.items[i].id = self.allocate_id();
             }
         }
     }
 
-    fn move_item_down(&mut self) {
-        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
-            if actual_idx < self.backlog.items.len() - 1 {
-                self.backlog.items.swap(actual_idx, actual_idx + 1);
-                // Update selection to follow the item
-                if let Some(new_visible_idx) = self.actual_to_visible(actual_idx + 1) {
-                    self.selected = new_visible_idx;
+    /// Restores the tree layout after items were reordered from outside (e.g.
+    /// by a merge): every subtask follows its parent, siblings keep their
+    /// relative order, and items whose parent is gone become top-level.
+    fn regroup(&mut self) {
+        fn place(items: &[BacklogItem], idx: usize, placed: &mut [bool], order: &mut Vec<usize>) {
+            placed[idx] = true;
+            order.push(idx);
+            for child in 0..items.len() {
+                if !placed[child] && items[child].parent == Some(items[idx].id) {
+                    place(items, child, placed, order);
                 }
-                let _ = self.save();
             }
         }
+
+        let ids: HashSet<u64> = self.items.iter().map(|i| i.id).collect();
+        let mut items = std::mem::take(&mut self.items);
+        for item in &mut items {
+            if item
+                .parent
+                .is_some_and(|p| p == item.id || !ids.contains(&p))
+            {
+                item.parent = None;
+            }
+        }
+
+        let mut placed = vec![false; items.len()];
+        let mut order = Vec::with_capacity(items.len());
+        for idx in 0..items.len() {
+            if !placed[idx] && items[idx].parent.is_none() {
+                place(&items, idx, &mut placed, &mut order);
+            }
+        }
+        // Whatever is left hangs off a parent cycle; break it at its first item
+        for idx in 0..items.len() {
+            if !placed[idx] {
+                items[idx].parent = None;
+                place(&items, idx, &mut placed, &mut order);
+            }
+        }
+
+        let mut slots: Vec<Option<BacklogItem>> = items.into_iter().map(Some).collect();
+        self.items = order.into_iter().filter_map(|i| slots[i].take()).collect();
     }
 
-    fn enter_edit_mode(&mut self) {
-        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
-            self.edit_buffer = self.backlog.items[actual_idx].description.clone();
-            self.edit_cursor = self.edit_buffer.len();
-            self.mode = Mode::Edit;
+    fn new_item(&mut self, description: String) -> BacklogItem {
+        BacklogItem {
+            id: self.allocate_id(),
+            description,
+            created_at: Utc::now(),
+            status: TODO.to_string(),
+            priority: None,
+            tags: Vec::new(),
+            due: None,
+            notes: None,
+            parent: None,
+            blocked_by: Vec::new(),
+            completed_at: None,
+            updated_at: None,
+            source: None,
+            closed_by: None,
+            started_at: None,
+            branch: None,
         }
     }
 
-    fn confirm_edit(&mut self) {
-        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
-            self.backlog.items[actual_idx].description = self.edit_buffer.clone();
-            let _ = self.save();
+    /// Appends a new pending item and returns its index
+    fn push(&mut self, description: String) -> usize {
+        let item = self.new_item(description);
+        self.items.push(item);
+        self.items.len() - 1
+    }
+
+    /// Adds a pending subtask as the last child of `parent_idx` and returns its index
+    fn push_under(&mut self, parent_idx: usize, description: String) -> usize {
+        let mut item = self.new_item(description);
+        item.parent = Some(self.items[parent_idx].id);
+        let idx = self.subtree_end(parent_idx);
+        self.items.insert(idx, item);
+        idx
+    }
+
+    /// Appends a subtree taken from another repo's backlog, giving its items
+    /// fresh IDs. Blockers outside the subtree and the code comment and branch
+    /// an item was linked to only meant something in the old repo, so they are
+    /// dropped.
+    fn adopt(&mut self, mut subtree: Vec<BacklogItem>) {
+        let ids: HashSet<u64> = subtree.iter().map(|item| item.id).collect();
+        for item in &mut subtree {
+            item.parent = item.parent.filter(|p| ids.contains(p));
+            item.blocked_by.retain(|b| ids.contains(b));
+            item.source = None;
+            item.branch = None;
+            item.touch();
         }
-        self.mode = Mode::Normal;
+        self.renumber_subtree(&mut subtree);
+        self.items.extend(subtree);
     }
 
-    fn cancel_edit(&mut self) {
-        self.mode = Mode::Normal;
+    /// Puts a subtree taken from the archive back and returns the index of its
+    /// root: under its original parent if that's still around, else at the end
+    fn restore(&mut self, mut subtree: Vec<BacklogItem>) -> usize {
+        // IDs handed out again meanwhile (e.g. by undoing the archiving with
+        // --force) would clash, so the subtree gets fresh ones then
+        if subtree.iter().any(|i| self.index_of(i.id).is_some()) {
+            self.renumber_subtree(&mut subtree);
+        }
+        let idx = match subtree[0].parent.and_then(|id| self.index_of(id)) {
+            Some(parent_idx) => self.subtree_end(parent_idx),
+            None => {
+                subtree[0].parent = None;
+                self.items.len()
+            }
+        };
+        self.items.splice(idx..idx, subtree);
+        self.prune_blockers();
+        self.assign_missing_ids();
+        self.items[idx].touch();
+        idx
     }
 
-    fn select_item(&mut self) {
-        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
-            self.output = Some(self.backlog.items[actual_idx].description.clone());
+    /// Gives a subtree from elsewhere (another repo, or the archive) IDs this
+    /// backlog hasn't handed out yet. References between its items follow
+    /// along; references to items outside it are left alone.
+    fn renumber_subtree(&mut self, subtree: &mut [BacklogItem]) {
+        let ids: HashMap<u64, u64> = subtree
+            .iter()
+            .map(|item| (item.id, self.allocate_id()))
+            .collect();
+        let renumber = |id: u64| ids.get(&id).copied().unwrap_or(id);
+        for item in subtree {
+            item.id = renumber(item.id);
+            item.parent = item.parent.map(renumber);
+            for blocker in &mut item.blocked_by {
+                *blocker = renumber(*blocker);
+            }
         }
     }
 
-    fn delete_selected(&mut self) {
-        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
-            self.backlog.items.remove(actual_idx);
-            let visible = self.visible_indices();
-            if visible.is_empty() {
-                self.selected = 0;
-            } else if self.selected >= visible.len() {
-                self.selected = visible.len() - 1;
+    fn index_of(&self, id: u64) -> Option<usize> {
+        self.items.iter().position(|i| i.id == id)
+    }
+
+    /// Index of an item's parent, if it has one that still exists
+    fn parent_index(&self, idx: usize) -> Option<usize> {
+        self.items[idx].parent.and_then(|id| self.index_of(id))
+    }
+
+    /// IDs of an item's ancestors, nearest first
+    fn ancestors(&self, idx: usize) -> Vec<u64> {
+        let mut ancestors = Vec::new();
+        let mut current = idx;
+        while let Some(parent) = self.parent_index(current) {
+            let id = self.items[parent].id;
+            // Guard against a hand-edited file with a parent cycle
+            if ancestors.contains(&id) {
+                break;
             }
-            let _ = self.save();
+            ancestors.push(id);
+            current = parent;
         }
-        self.mode = Mode::Normal;
+        ancestors
     }
 
-    fn enter_add_mode(&mut self) {
-        self.edit_buffer = String::new();
-        self.edit_cursor = 0;
-        self.mode = Mode::Add;
+    fn depth(&self, idx: usize) -> usize {
+        self.ancestors(idx).len()
     }
 
-    fn confirm_add(&mut self) {
-        if !self.edit_buffer.is_empty() {
-            self.backlog.items.push(BacklogItem {
-                description: self.edit_buffer.clone(),
-                created_at: Utc::now(),
-                done: false,
-            });
-            // Select the newly added item (it's not done, so always visible)
-            let visible = self.visible_indices();
-            self.selected = visible.len() - 1;
-            let _ = self.save();
+    /// End (exclusive) of an item's subtree. Subtasks are always stored directly
+    /// after their parent, so the subtree is the contiguous run of descendants.
+    fn subtree_end(&self, idx: usize) -> usize {
+        let id = self.items[idx].id;
+        let mut end = idx + 1;
+        while end < self.items.len() && self.ancestors(end).contains(&id) {
+            end += 1;
         }
-        self.mode = Mode::Normal;
+        end
     }
 
-    fn cancel_add(&mut self) {
-        self.mode = Mode::Normal;
+    /// `(done, total)` over an item's direct subtasks, or `None` if it has none
+    fn progress(&self, idx: usize) -> Option<(usize, usize)> {
+        let id = self.items[idx].id;
+        let children: Vec<_> = self.items.iter().filter(|i| i.parent == Some(id)).collect();
+        if children.is_empty() {
+            None
+        } else {
+            Some((
+                children.iter().filter(|i| i.is_done()).count(),
+                children.len(),
+            ))
+        }
     }
-}
 
-/// A custom widget for rendering the backlog list with wrapped items
-struct BacklogList<'a> {
-    /// Visible items: (original_index, item)
-    items: Vec<(usize, &'a BacklogItem)>,
-    selected: usize,
-    scroll_offset: usize,
-    title: String,
-    /// When true, use sequential numbering (1, 2, 3...) instead of original indices
-    renumber: bool,
-}
+    /// Removes an item together with all of its subtasks
+    fn remove_subtree(&mut self, idx: usize) -> Vec<BacklogItem> {
+        let end = self.subtree_end(idx);
+        let removed = self.items.drain(idx..end).collect();
+        self.prune_blockers();
+        removed
+    }
 
-impl<'a> BacklogList<'a> {
-    fn new(
-        items: Vec<(usize, &'a BacklogItem)>,
-        selected: usize,
-        scroll_offset: usize,
-        title: String,
-        renumber: bool,
-    ) -> Self {
-        Self {
-            items,
-            selected,
-            scroll_offset,
-            title,
-            renumber,
+    /// Removes several items with their subtasks. Items are given by ID so that
+    /// earlier removals can't shift the positions of later ones; an item already
+    /// removed as part of an earlier subtree is skipped. Returns each removed subtree.
+    fn remove_all(&mut self, ids: &[u64]) -> Vec<Vec<BacklogItem>> {
+        let mut removed = Vec::new();
+        for &id in ids {
+            if let Some(idx) = self.index_of(id) {
+                let end = self.subtree_end(idx);
+                removed.push(self.items.drain(idx..end).collect());
+            }
         }
+        self.prune_blockers();
+        removed
     }
-}
 
-impl Widget for BacklogList<'_> {
-    fn render(self, area: Rect, buf: &mut ratatui::buffer::Buffer) {
-        let block = Block::default().borders(Borders::ALL).title(self.title);
-        let inner = block.inner(area);
-        block.render(area, buf);
+    /// IDs of the completed items whose subtasks are all done as well (optionally
+    /// only those completed before `cutoff`), plus the number of completed items
+    /// passed over because they still have pending subtasks
+    fn completed_subtrees(&self, cutoff: Option<DateTime<Utc>>) -> (Vec<u64>, usize) {
+        let mut ids = Vec::new();
+        let mut kept = 0;
+        for idx in 0..self.items.len() {
+            if !self.items[idx].is_done() {
+                continue;
+            }
+            let subtree = &self.items[idx..self.subtree_end(idx)];
+            if !subtree.iter().all(|i| i.is_done()) {
+                kept += 1;
+            } else if cutoff.is_none_or(|cutoff| {
+                // Files from before completion times were kept count as old enough
+                subtree
+                    .iter()
+                    .all(|i| i.completed_at.is_none_or(|at| at < cutoff))
+            }) {
+                ids.push(self.items[idx].id);
+            }
+        }
+        (ids, kept)
+    }
 
-        if inner.width < 10 || inner.height < 1 {
-            return;
+    /// Drops references to items that no longer exist from other items' blockers
+    fn prune_blockers(&mut self) {
+        let ids: HashSet<u64> = self.items.iter().map(|i| i.id).collect();
+        for item in &mut self.items {
+            item.blocked_by.retain(|id| ids.contains(id));
         }
+    }
 
-        let prefix_width: u16 = 8; // "1. [x] " = 8 chars
-        let text_width = inner.width.saturating_sub(prefix_width) as usize;
+    /// Pending items that the item at `idx` is still waiting for
+    fn pending_blockers(&self, idx: usize) -> Vec<&BacklogItem> {
+        self.items[idx]
+            .blocked_by
+            .iter()
+            .filter_map(|id| self.index_of(*id))
+            .map(|i| &self.items[i])
+            .filter(|blocker| !blocker.is_done())
+            .collect()
+    }
 
-        let mut y = 0u16;
-        for (visible_idx, (original_idx, item)) in
-            self.items.iter().enumerate().skip(self.scroll_offset)
-        {
-            if y >= inner.height {
-                break;
+    fn is_blocked(&self, idx: usize) -> bool {
+        !self.pending_blockers(idx).is_empty()
+    }
+
+    /// Whether `from` waits on `target`, directly or through other blockers
+    fn depends_on(&self, from: u64, target: u64) -> bool {
+        let mut stack = vec![from];
+        let mut seen = HashSet::new();
+        while let Some(id) = stack.pop() {
+            if id == target {
+                return true;
+            }
+            if seen.insert(id)
+                && let Some(idx) = self.index_of(id)
+            {
+                stack.extend(&self.items[idx].blocked_by);
             }
+        }
+        false
+    }
 
-            let checkbox = if item.done { "[x]" } else { "[ ]" };
-            let display_num = if self.renumber {
-                visible_idx + 1
-            } else {
-                original_idx + 1
-            };
-            let prefix = format!("{}. {} ", display_num, checkbox);
+    /// Makes the item at `idx` wait for the one at `blocker_idx`, refusing to
+    /// create a dependency cycle
+    fn add_blocker(&mut self, idx: usize, blocker_idx: usize) -> Result<(), String> {
+        let id = self.items[idx].id;
+        let blocker_id = self.items[blocker_idx].id;
+        if id == blocker_id {
+            return Err(format!("@{} can't block itself", id));
+        }
+        if self.depends_on(blocker_id, id) {
+            return Err(format!(
+                "@{} already waits on @{}; blocking would create a cycle",
+                blocker_id, id
+            ));
+        }
+        if !self.items[idx].blocked_by.contains(&blocker_id) {
+            self.items[idx].blocked_by.push(blocker_id);
+            self.items[idx].touch();
+        }
+        Ok(())
+    }
 
-            let style = if visible_idx == self.selected {
-                if item.done {
-                    Style::default()
-                        .fg(Color::DarkGray)
-                        .add_modifier(Modifier::REVERSED)
-                } else {
-                    Style::default().add_modifier(Modifier::REVERSED)
-                }
-            } else if item.done {
-                Style::default().fg(Color::DarkGray)
-            } else {
-                Style::default()
-            };
+    /// Moves an item and its subtasks so they start at `to`, an index into the
+    /// list as it is once the subtree has been taken out
+    fn move_subtree(&mut self, idx: usize, to: usize) {
+        let end = self.subtree_end(idx);
+        let subtree: Vec<_> = self.items.drain(idx..end).collect();
+        self.items.splice(to..to, subtree);
+    }
 
-            // Wrap the description text
-            let desc_chars: Vec<char> = item.description.chars().collect();
-            let lines: Vec<String> = if text_width > 0 && !desc_chars.is_empty() {
-                desc_chars
-                    .chunks(text_width)
-                    .map(|chunk| chunk.iter().collect())
-                    .collect()
-            } else {
-                vec![item.description.clone()]
+    /// The closest earlier item with the same parent, if any
+    fn previous_sibling(&self, idx: usize) -> Option<usize> {
+        let parent = self.parent_index(idx);
+        let start = parent.map_or(0, |p| p + 1);
+        (start..idx).rev().find(|&j| self.parent_index(j) == parent)
+    }
+
+    /// Swaps an item (with its subtasks) with its previous or next sibling
+    fn move_among_siblings(&mut self, idx: usize, up: bool) -> bool {
+        if up {
+            let Some(prev) = self.previous_sibling(idx) else {
+                return false;
             };
+            self.move_subtree(idx, prev);
+        } else {
+            let end = self.subtree_end(idx);
+            if end >= self.items.len() || self.parent_index(end) != self.parent_index(idx) {
+                return false;
+            }
+            let next_end = self.subtree_end(end);
+            self.move_subtree(idx, next_end - (end - idx));
+        }
+        true
+    }
 
-            for (line_idx, line_text) in lines.iter().enumerate() {
-                if y >= inner.height {
-                    break;
-                }
+    /// Moves an item past its siblings until it reaches the index `to`, or as
+    /// close as it gets without leaving its parent. Returns its new index.
+    fn move_to(&mut self, idx: usize, to: usize) -> usize {
+        let id = self.items[idx].id;
+        let up = to < idx;
+        let mut idx = idx;
+        while (if up { idx > to } else { idx < to }) && self.move_among_siblings(idx, up) {
+            idx = self.index_of(id).unwrap_or(idx);
+        }
+        self.items[idx].touch();
+        idx
+    }
 
-                let x_start = inner.x;
-                let y_pos = inner.y + y;
+    /// Makes an item the last subtask of its previous sibling
+    fn indent(&mut self, idx: usize) -> bool {
+        let Some(prev) = self.previous_sibling(idx) else {
+            return false;
+        };
+        // Everything between the sibling and this item is already the sibling's subtree
+        self.items[idx].parent = Some(self.items[prev].id);
+        self.items[idx].touch();
+        true
+    }
 
-                // Render prefix only on first line
-                if line_idx == 0 {
-                    for (j, ch) in prefix.chars().enumerate() {
-                        if (j as u16) < prefix_width {
-                            buf[(x_start + j as u16, y_pos)]
-                                .set_char(ch)
-                                .set_style(style);
-                        }
-                    }
-                } else {
-                    // Indent continuation lines
-                    for j in 0..prefix_width {
-                        buf[(x_start + j, y_pos)].set_char(' ').set_style(style);
-                    }
-                }
+    /// Makes an item a sibling of its parent, placed right after the parent's subtree
+    fn outdent(&mut self, idx: usize) -> bool {
+        let Some(parent) = self.parent_index(idx) else {
+            return false;
+        };
+        let len = self.subtree_end(idx) - idx;
+        let parent_end = self.subtree_end(parent);
+        self.items[idx].parent = self.items[parent].parent;
+        self.items[idx].touch();
+        self.move_subtree(idx, parent_end - len);
+        true
+    }
 
-                // Render the text portion
-                for (j, ch) in line_text.chars().enumerate() {
-                    let x_pos = x_start + prefix_width + j as u16;
-                    if x_pos < inner.x + inner.width {
-                        buf[(x_pos, y_pos)].set_char(ch).set_style(style);
+    /// Formats the item at `idx` as a line of the indented tree listing,
+    /// noting any pending blockers
+    fn format_line(&self, idx: usize) -> String {
+        let line = format_tree_item(
+            idx + 1,
+            &self.items[idx],
+            self.depth(idx),
+            self.progress(idx),
+        );
+        let blockers = self.pending_blockers(idx);
+        if blockers.is_empty() || self.items[idx].is_done() {
+            return line;
+        }
+        let ids: Vec<String> = blockers.iter().map(|b| format!("@{}", b.id)).collect();
+        format!("{} (blocked by {})", line, ids.join(", "))
+    }
+
+    /// Resolves command-line item specs to the IDs of the items they name, in
+    /// backlog order and without duplicates
+    fn select(&self, specs: &[ItemSpec]) -> Result<Vec<u64>, Failure> {
+        let mut selected = HashSet::new();
+        for spec in specs {
+            match *spec {
+                ItemSpec::One(item_ref) => {
+                    let idx = self
+                        .resolve(item_ref)
+                        .ok_or_else(|| Failure::no_such_item(item_ref))?;
+                    selected.insert(idx);
+                }
+                ItemSpec::Range(start, end) => {
+                    if start == 0 || end > self.items.len() {
+                        return Err(Failure {
+                            code: EXIT_NO_SUCH_ITEM,
+                            message: format!(
+                                "Range {}-{} is out of range (1-{})",
+                                start,
+                                end,
+                                self.items.len()
+                            ),
+                        });
                     }
+                    selected.extend(start - 1..end);
                 }
+            }
+        }
+        let mut indices: Vec<_> = selected.into_iter().collect();
+        indices.sort();
+        Ok(indices.into_iter().map(|i| self.items[i].id).collect())
+    }
 
-                // Fill remaining width with style (for reversed highlight)
-                let text_end = prefix_width + line_text.chars().count() as u16;
-                for j in text_end..inner.width {
-                    buf[(x_start + j, y_pos)].set_char(' ').set_style(style);
-                }
+    /// IDs of the items whose description contains `text`, ignoring case
+    fn matching(&self, text: &str) -> Vec<u64> {
+        let text = text.to_lowercase();
+        self.items
+            .iter()
+            .filter(|i| i.description.to_lowercase().contains(&text))
+            .map(|i| i.id)
+            .collect()
+    }
 
-                y += 1;
+    /// The item at `idx` as an output record
+    fn record<'a>(&'a self, repo: &'a str, idx: usize) -> ItemRecord<'a> {
+        let item = &self.items[idx];
+        ItemRecord {
+            repo,
+            position: idx + 1,
+            item,
+            done: item.is_done(),
+            blocked: !item.is_done() && self.is_blocked(idx),
+            overdue: item.is_overdue(),
+        }
+    }
+
+    /// Resolves a command-line item reference to an index into `items`
+    fn resolve(&self, item_ref: ItemRef) -> Option<usize> {
+        match item_ref {
+            ItemRef::Position(n) if n >= 1 && n <= self.items.len() => Some(n - 1),
+            ItemRef::Position(_) => None,
+            ItemRef::Id(id) => self.items.iter().position(|i| i.id == id),
+        }
+    }
+
+    /// Items paired with their 1-based position, in the requested order
+    fn sorted(&self, sort: SortKey) -> Vec<(usize, &BacklogItem)> {
+        let mut items: Vec<_> = self
+            .items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (i + 1, item))
+            .collect();
+        match sort {
+            SortKey::Position => {}
+            SortKey::Priority => items.sort_by_key(|(_, item)| item.priority_rank()),
+            SortKey::Due => items.sort_by_key(|(_, item)| (item.due.is_none(), item.due)),
+            SortKey::Created => items.sort_by_key(|(_, item)| item.created_at),
+            SortKey::Updated => {
+                items.sort_by_key(|(_, item)| std::cmp::Reverse(item.last_updated()))
+            }
+            SortKey::Completed => {
+                items.sort_by_key(|(_, item)| std::cmp::Reverse(item.completed_at))
             }
         }
+        items
+    }
+
+    /// The item to work on next: one in the `started` status if there is any,
+    /// else the highest-priority todo one, earliest first. Items in any other
+    /// status (such as review or blocked) wait on someone else, so they are
+    /// skipped, as are blocked items and parents whose subtasks aren't finished
+    fn next_item(&self, started: &str) -> Option<&BacklogItem> {
+        self.items
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| i.status == TODO || i.status == started)
+            .filter(|(_, i)| {
+                !self
+                    .items
+                    .iter()
+                    .any(|c| c.parent == Some(i.id) && !c.is_done())
+            })
+            .filter(|(idx, _)| !self.is_blocked(*idx))
+            .min_by_key(|(_, i)| (i.status != started, i.priority_rank()))
+            .map(|(_, i)| i)
     }
 }
 
-fn run_tui(backlog_path: PathBuf) -> io::Result<Option<String>> {
-    let backlog = load_backlog(&backlog_path);
+#[derive(Serialize, Deserialize)]
+struct GlobalIndex {
+    /// File format version, see `INDEX_UPGRADES`
+    #[serde(default)]
+    version: u32,
+    /// Maps repo paths to their backlog file paths
+    repos: Vec<String>,
+}
 
-    if backlog.items.is_empty() {
-        return Ok(None);
+impl Default for GlobalIndex {
+    fn default() -> Self {
+        GlobalIndex {
+            version: INDEX_VERSION,
+            repos: Vec::new(),
+        }
     }
+}
 
-    enable_raw_mode()?;
-    let mut stdout = stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+/// One step in bringing a file written by an older version up to date
+struct Upgrade {
+    /// Format version the step produces
+    to: u32,
+    /// What the step changes, as reported by `migrate --dry-run`
+    description: &'static str,
+    apply: fn(&mut serde_json::Map<String, Value>),
+}
 
-    let mut app = App::new(backlog, backlog_path);
+/// Format version of backlog and archive files written by this build; bump it
+/// together with a new step in `BACKLOG_UPGRADES`
+const BACKLOG_VERSION: u32 = 5;
 
-    loop {
-        let has_input_box = app.mode == Mode::Edit || app.mode == Mode::Add;
+/// Upgrades for backlog files, in order. Files without a version are version 0.
+const BACKLOG_UPGRADES: &[Upgrade] = &[
+    Upgrade {
+        to: 1,
+        description: "give every item a stable ID",
+        apply: upgrade_item_ids,
+    },
+    // Only adds a field, but older builds must not drop it when saving
+    Upgrade {
+        to: 2,
+        description: "allow items to link to the code comment they came from",
+        apply: |_| {},
+    },
+    Upgrade {
+        to: 3,
+        description: "allow items to record the commit that closed them",
+        apply: |_| {},
+    },
+    Upgrade {
+        to: 4,
+        description: "allow items to record when work started and on which branch",
+        apply: |_| {},
+    },
+    Upgrade {
+        to: 5,
+        description: "replace the done flag with a status (todo, doing or done)",
+        apply: |backlog| {
+            let items = backlog.get_mut("items").and_then(Value::as_array_mut);
+            for item in items.into_iter().flatten().filter_map(Value::as_object_mut) {
+                upgrade_item_status(item);
+            }
+        },
+    },
+];
 
-        // First pass: calculate layout to get actual list height
-        let size = terminal.size()?;
-        let area = Rect::new(0, 0, size.width, size.height);
-        let constraints = if has_input_box {
-            vec![
-                Constraint::Min(3),
-                Constraint::Length(5),
-                Constraint::Length(3),
-            ]
+/// Format version of the global index written by this build
+const INDEX_VERSION: u32 = 1;
+
+/// Upgrades for the global index, in order (version 0 and 1 only differ in
+/// the version field)
+const INDEX_UPGRADES: &[Upgrade] = &[];
+
+/// Turns an item's `done` flag into a status; items `backlog start` began on
+/// are "doing"
+fn upgrade_item_status(item: &mut serde_json::Map<String, Value>) {
+    let done = item
+        .remove("done")
+        .and_then(|d| d.as_bool())
+        .unwrap_or(false);
+    if !item.contains_key("status") {
+        let status = if done {
+            DONE
+        } else if item.contains_key("started_at") {
+            DOING
         } else {
-            vec![Constraint::Min(3), Constraint::Length(3)]
+            TODO
         };
-        let chunks = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints(constraints.clone())
-            .split(area);
-
-        // Inner height = chunk height - 2 for borders
-        // For now, use a conservative estimate: assume each item takes ~2 rows on average
-        let list_height = (chunks[0].height.saturating_sub(2) / 2) as usize;
+        item.insert("status".to_string(), status.into());
+    }
+}
 
-        // Adjust scroll to keep selection visible
-        if app.selected < app.scroll_offset {
-            app.scroll_offset = app.selected;
-        } else if list_height > 0 && app.selected >= app.scroll_offset + list_height {
-            app.scroll_offset = app.selected - list_height + 1;
+fn upgrade_item_ids(backlog: &mut serde_json::Map<String, Value>) {
+    let next_id = backlog.get("next_id").and_then(Value::as_u64).unwrap_or(0);
+    let Some(items) = backlog.get_mut("items").and_then(Value::as_array_mut) else {
+        return;
+    };
+    let max_id = items
+        .iter()
+        .filter_map(|item| item.get("id")?.as_u64())
+        .max()
+        .unwrap_or(0);
+    let mut next = next_id.max(max_id + 1);
+    for item in items.iter_mut().filter_map(Value::as_object_mut) {
+        if item.get("id").and_then(Value::as_u64).unwrap_or(0) == 0 {
+            item.insert("id".to_string(), next.into());
+            next += 1;
         }
+    }
+    backlog.insert("next_id".to_string(), next.into());
+}
 
-        terminal.draw(|f| {
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints(constraints.clone())
-                .split(f.area());
-
-            // Build visible items list with original indices
-            let visible_items: Vec<(usize, &BacklogItem)> = app
-                .backlog
-                .items
-                .iter()
-                .enumerate()
-                .filter(|(_, item)| !app.hide_completed || !item.done)
-                .collect();
+/// Format version a file's content was written with
+fn format_version(value: &Value) -> u32 {
+    value
+        .get("version")
+        .and_then(Value::as_u64)
+        .map_or(0, |v| v as u32)
+}
 
-            let title = if app.hide_completed {
-                "Backlog (hiding completed)".to_string()
-            } else {
-                "Backlog".to_string()
-            };
+/// The upgrade steps a file at `version` still needs. Files from a newer
+/// version are refused rather than read, since saving them again would drop
+/// whatever this build doesn't know about.
+fn pending_upgrades(
+    path: &Path,
+    version: u32,
+    steps: &'static [Upgrade],
+    current: u32,
+) -> io::Result<Vec<&'static Upgrade>> {
+    if version > current {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} was written by a newer version of backlog (format {}, this one reads up to {}). Please upgrade backlog.",
+                path.display(),
+                version,
+                current
+            ),
+        ));
+    }
+    Ok(steps.iter().filter(|step| step.to > version).collect())
+}
 
-            let list = BacklogList::new(
-                visible_items,
-                app.selected,
-                app.scroll_offset,
-                title,
-                app.hide_completed,
-            );
-            f.render_widget(list, chunks[0]);
+/// Brings parsed file content up to the current format
+fn upgrade(
+    value: &mut Value,
+    path: &Path,
+    steps: &'static [Upgrade],
+    current: u32,
+) -> io::Result<()> {
+    let pending = pending_upgrades(path, format_version(value), steps, current)?;
+    if let Some(map) = value.as_object_mut() {
+        for step in &pending {
+            (step.apply)(map);
+        }
+        map.insert("version".to_string(), current.into());
+    }
+    Ok(())
+}
 
-            if has_input_box {
-                let before_cursor: String = app.edit_buffer.chars().take(app.edit_cursor).collect();
-                let cursor_char: String = app
-                    .edit_buffer
-                    .chars()
-                    .skip(app.edit_cursor)
-                    .take(1)
-                    .collect();
-                let after_cursor: String =
-                    app.edit_buffer.chars().skip(app.edit_cursor + 1).collect();
+/// Format version of the file at `path`, or `None` if there is no such file
+fn stored_version(path: &Path) -> io::Result<Option<u32>> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let value: Value = serde_json::from_str(&content).map_err(|e| parse_error(path, &e))?;
+            Ok(Some(format_version(&value)))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
-                let cursor_display = if cursor_char.is_empty() {
-                    " ".to_string()
-                } else {
-                    cursor_char
-                };
+/// Describes the upgrades a file at `version` needs, for `backlog migrate`
+fn upgrade_plan(
+    path: &Path,
+    version: Option<u32>,
+    steps: &'static [Upgrade],
+    current: u32,
+) -> Result<Vec<String>, Failure> {
+    let Some(version) = version else {
+        return Ok(Vec::new());
+    };
+    let pending = pending_upgrades(path, version, steps, current)?;
+    if version == current {
+        return Ok(Vec::new());
+    }
+    let mut plan = vec![format!(
+        "Upgrade {} from format {} to {}",
+        path.display(),
+        version,
+        current
+    )];
+    plan.extend(
+        pending
+            .iter()
+            .map(|step| format!("  - {}", step.description)),
+    );
+    Ok(plan)
+}
 
-                let input_text = Line::from(vec![
-                    Span::raw(before_cursor),
-                    Span::styled(
-                        cursor_display,
-                        Style::default().bg(Color::White).fg(Color::Black),
-                    ),
-                    Span::raw(after_cursor),
-                ]);
+/// Parses a versioned file, upgrading it first if it's from an older version
+fn parse_versioned<T: serde::de::DeserializeOwned>(
+    content: &str,
+    path: &Path,
+    steps: &'static [Upgrade],
+    current: u32,
+) -> io::Result<T> {
+    let mut value: Value = serde_json::from_str(content).map_err(|e| parse_error(path, &e))?;
+    let version = format_version(&value);
+    upgrade(&mut value, path, steps, current)?;
+    if version == current {
+        // Parse the text again so errors point at a line and column
+        return serde_json::from_str(content).map_err(|e| parse_error(path, &e));
+    }
+    serde_json::from_value(value).map_err(|e| parse_error(path, &e))
+}
 
-                let title = if app.mode == Mode::Add { "Add" } else { "Edit" };
-                let input_box = Paragraph::new(input_text)
-                    .wrap(ratatui::widgets::Wrap { trim: false })
-                    .block(Block::default().borders(Borders::ALL).title(title));
-                f.render_widget(input_box, chunks[1]);
-            }
+/// Parses a backlog file, refusing one where several items share an ID (as
+/// after git merged two branches that each added items line by line), since
+/// commands naming that ID would silently pick one of them
+fn parse_backlog(content: &str, path: &Path) -> io::Result<Backlog> {
+    let mut backlog: Backlog = parse_versioned(content, path, BACKLOG_UPGRADES, BACKLOG_VERSION)?;
+    if let Some(id) = backlog.duplicate_id() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} has more than one item with ID @{}. Run 'backlog doctor --fix' to give the duplicates new IDs.",
+                path.display(),
+                id
+            ),
+        ));
+    }
+    // Never hand out an ID the file already uses
+    let max_id = backlog.items.iter().map(|i| i.id).max().unwrap_or(0);
+    backlog.next_id = backlog.next_id.max(max_id + 1);
+    Ok(backlog)
+}
 
-            let help_chunk = if has_input_box { chunks[2] } else { chunks[1] };
+/// How a repo stores its backlog on disk
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Storage {
+    /// Everything in a single .backlog/backlog.json
+    #[default]
+    File,
+    /// One file per item under .backlog/items/, with the order in .backlog/order
+    Items,
+}
 
-            let help_text = match app.mode {
-                Mode::Edit | Mode::Add => "Enter:confirm  Esc:cancel",
-                Mode::ConfirmDelete => "Delete item? y:yes  n/Esc:cancel",
-                Mode::Normal => {
-                    "a:add  j/k:nav  x:toggle  e:edit  dd:del  K/J:move  h:hide done  q:quit"
-                }
-            };
-            let help_style = if app.mode == Mode::ConfirmDelete {
-                Style::default().fg(Color::Red)
-            } else {
-                Style::default().fg(Color::DarkGray)
-            };
-            let help = Paragraph::new(help_text)
-                .style(help_style)
-                .block(Block::default().borders(Borders::ALL));
-            f.render_widget(help, help_chunk);
-        })?;
+/// Per-repo settings, kept in .backlog/config.json and shared with the backlog
+#[derive(Serialize, Deserialize, Default)]
+struct RepoConfig {
+    #[serde(default)]
+    storage: Storage,
+    /// Prepended to the names of branches made by `backlog start`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    branch_prefix: Option<String>,
+    /// The repo's workflow in order, e.g. ["todo", "doing", "review", "done"]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    statuses: Vec<String>,
+}
 
-        if let Event::Key(key) = event::read()? {
-            if key.kind != KeyEventKind::Press {
-                continue;
+impl RepoConfig {
+    /// Statuses items move through; always starts with todo and ends with done
+    fn workflow(&self) -> Vec<String> {
+        let mut workflow = vec![TODO.to_string()];
+        if self.statuses.is_empty() {
+            workflow.push(DOING.to_string());
+        }
+        for status in &self.statuses {
+            let status = status.trim().to_lowercase();
+            if !status.is_empty() && !workflow.contains(&status) && status != DONE {
+                workflow.push(status);
             }
-
+        }
+        workflow.push(DONE.to_string());
+        workflow
+    }
+
+    fn branch_prefix(&self) -> &str {
+        self.branch_prefix.as_deref().unwrap_or("backlog/")
+    }
+
+    /// The status `backlog start` sets: the workflow's first step after todo,
+    /// "doing" unless configured otherwise
+    fn started_status(&self) -> String {
+        self.workflow()
+            .into_iter()
+            .nth(1)
+            .filter(|s| s != DONE)
+            .unwrap_or_else(|| DOING.to_string())
+    }
+}
+
+fn get_repo_root() -> Option<PathBuf> {
+    let current_dir = std::env::current_dir().ok()?;
+    let mut dir = current_dir.as_path();
+
+    loop {
+        if dir.join(".git").exists() {
+            return Some(dir.to_path_buf());
+        }
+        dir = dir.parent()?;
+    }
+}
+
+fn get_repo_backlog_path() -> Option<PathBuf> {
+    Some(repo_backlog_file(&get_repo_root()?))
+}
+
+/// Whether a repo still has its backlog in the .todo directory used by older
+/// versions
+fn has_old_backlog_dir(repo: &Path) -> bool {
+    repo.join(".todo").exists() && !repo.join(".backlog").exists()
+}
+
+/// Backlog file for a repo, moving an old .todo directory to .backlog first
+fn repo_backlog_file(repo: &Path) -> PathBuf {
+    let new_dir = repo.join(".backlog");
+    let old_dir = repo.join(".todo");
+
+    if has_old_backlog_dir(repo) && fs::rename(&old_dir, &new_dir).is_err() {
+        // If rename fails, fall back to old path
+        return old_dir.join("backlog.json");
+    }
+
+    new_dir.join("backlog.json")
+}
+
+fn get_global_dir() -> PathBuf {
+    dirs::home_dir()
+        .expect("Could not find home directory")
+        .join(".backlog")
+}
+
+fn get_global_index_path() -> PathBuf {
+    get_global_dir().join("index.json")
+}
+
+/// Keeps a copy of an unparseable backlog file next to it and returns where.
+/// An existing copy of different content is never overwritten, so partly
+/// fixing the file by hand and running another command can't lose the
+/// original; the new content gets a timestamped name instead.
+fn keep_backup(path: &Path, content: &str) -> io::Result<PathBuf> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let bak = path.with_file_name(format!("{}.bak", file_name));
+    let mut copies = vec![bak.clone()];
+    if let Ok(entries) = fs::read_dir(path.parent().unwrap_or(Path::new("."))) {
+        copies.extend(entries.flatten().map(|e| e.path()).filter(|p| {
+            p.file_name().is_some_and(|n| {
+                let n = n.to_string_lossy();
+                n.starts_with(&format!("{}.", file_name)) && n.ends_with(".bak")
+            })
+        }));
+    }
+    if let Some(same) = copies
+        .into_iter()
+        .find(|copy| fs::read_to_string(copy).is_ok_and(|c| c == content))
+    {
+        return Ok(same);
+    }
+
+    let target = if bak.exists() {
+        let stamp = Local::now().format("%Y%m%d-%H%M%S");
+        path.with_file_name(format!("{}.{}.bak", file_name, stamp))
+    } else {
+        bak
+    };
+    fs::write(&target, content)?;
+    Ok(target)
+}
+
+/// Builds the error reported when a backlog or index file fails to parse
+fn parse_error(path: &Path, e: &serde_json::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        // serde_json's message already ends with "at line L column C"
+        format!("{} is corrupt: {}", path.display(), e),
+    )
+}
+
+/// Loads a backlog, refusing to fall back to an empty one when the file is
+/// unreadable so that a later save can never wipe out existing items. A copy
+/// of a corrupt file is kept next to it for `backlog doctor`.
+fn load_backlog(path: &PathBuf) -> io::Result<Backlog> {
+    if uses_item_files(path)? {
+        return read_item_files(path);
+    }
+    if !path.exists() {
+        return Ok(Backlog::default());
+    }
+    let content = fs::read_to_string(path)?;
+    parse_backlog(&content, path).map_err(|e| {
+        if e.kind() == io::ErrorKind::InvalidData
+            && let Ok(bak) = keep_backup(path, &content)
+        {
+            return io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}\nA copy was saved to {}.", e, bak.display()),
+            );
+        }
+        e
+    })
+}
+
+/// Loads a backlog for a CLI command, exiting with a message if it can't be read
+fn load_backlog_or_exit(path: &PathBuf) -> Backlog {
+    match load_backlog(path) {
+        Ok(backlog) => backlog,
+        // Only a corrupt backlog.json gets a backup copy
+        Err(e)
+            if e.kind() == io::ErrorKind::InvalidData && uses_item_files(path).unwrap_or(true) =>
+        {
+            fail(
+                EXIT_CORRUPT,
+                format!("{}\nRefusing to modify the backlog until it is fixed.", e),
+            )
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => fail(
+            EXIT_CORRUPT,
+            format!(
+                "{}\nRefusing to modify it; run 'backlog doctor' to recover items.",
+                e
+            ),
+        ),
+        Err(e) => fail(EXIT_FAILURE, e),
+    }
+}
+
+/// Replaces `path` with `content` so readers only ever see the old or the new
+/// file: the data is written and fsynced to a temp file, then renamed over it.
+fn write_atomic(path: &Path, content: &str) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = parent.join(format!("{}.{}.tmp", file_name, std::process::id()));
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return result;
+    }
+
+    // Make the rename itself durable
+    #[cfg(unix)]
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Takes an exclusive advisory lock guarding load-modify-save cycles on the
+/// file at `path`. The lock is released when the returned file is dropped.
+fn lock_file(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.with_extension("lock"))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+/// Locks a backlog for a CLI command, exiting with a message on failure
+fn lock_backlog_or_exit(path: &Path) -> File {
+    match lock_file(path) {
+        Ok(lock) => lock,
+        Err(e) => {
+            fail(EXIT_FAILURE, format!("Failed to lock backlog: {}", e));
+        }
+    }
+}
+
+/// Appends whichever of `entries` are missing from a line-based file such as
+/// .gitignore, creating it if needed
+fn ensure_lines(path: &Path, entries: &[&str]) -> io::Result<()> {
+    let existing = fs::read_to_string(path).unwrap_or_default();
+    let missing: Vec<&str> = entries
+        .iter()
+        .copied()
+        .filter(|entry| !existing.lines().any(|line| line == *entry))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{}", missing.join("\n"))
+}
+
+/// Drops `entries` from a line-based file written by `ensure_lines`, removing
+/// the file once nothing else is left in it
+fn remove_lines(path: &Path, entries: &[&str]) -> io::Result<()> {
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let kept: Vec<&str> = existing
+        .lines()
+        .filter(|line| !entries.contains(line))
+        .collect();
+    if kept.len() == existing.lines().count() {
+        return Ok(());
+    }
+    if kept.iter().all(|line| line.trim().is_empty()) {
+        return fs::remove_file(path);
+    }
+    write_atomic(path, &(kept.join("\n") + "\n"))
+}
+
+/// Keeps lock, temp and backup files and the local undo history out of
+/// commits of a shared .backlog/
+fn ignore_local_files(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => ensure_lines(
+            &parent.join(".gitignore"),
+            &["*.lock", "*.tmp", "*.bak", "history.json"],
+        ),
+        None => Ok(()),
+    }
+}
+
+fn save_backlog(path: &Path, backlog: &Backlog) -> std::io::Result<()> {
+    ignore_local_files(path)?;
+    if uses_item_files(path)? {
+        write_item_files(path, backlog)
+    } else {
+        write_backlog_file(path, backlog)
+    }
+}
+
+/// Writes a backlog as a single JSON file
+fn write_backlog_file(path: &Path, backlog: &Backlog) -> io::Result<()> {
+    let content = serde_json::to_string_pretty(backlog)?;
+    write_atomic(path, &content)
+}
+
+fn config_path(backlog_path: &Path) -> PathBuf {
+    backlog_path.with_file_name("config.json")
+}
+
+/// Loads the repo's settings; a missing file means the defaults
+fn load_config(backlog_path: &Path) -> io::Result<RepoConfig> {
+    let path = config_path(backlog_path);
+    match fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content).map_err(|e| parse_error(&path, &e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(RepoConfig::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn save_config(backlog_path: &Path, config: &RepoConfig) -> io::Result<()> {
+    let content = serde_json::to_string_pretty(config)?;
+    write_atomic(&config_path(backlog_path), &content)
+}
+
+/// Whether `path` is a repo's main backlog and that repo keeps it as one
+/// file per item (archives are always a single file)
+fn uses_item_files(path: &Path) -> io::Result<bool> {
+    if path.file_name() != Some("backlog.json".as_ref()) {
+        return Ok(false);
+    }
+    Ok(load_config(path)?.storage == Storage::Items)
+}
+
+fn items_dir(backlog_path: &Path) -> PathBuf {
+    backlog_path.with_file_name("items")
+}
+
+fn order_path(backlog_path: &Path) -> PathBuf {
+    backlog_path.with_file_name("order")
+}
+
+/// File name for an item in the items directory. The creation time keeps the
+/// names of items that two branches both numbered `id` apart.
+fn item_file_name(item: &BacklogItem) -> String {
+    format!("{}-{:x}.json", item.id, item.created_at.timestamp_micros())
+}
+
+/// Contents of the order file of a backlog kept as one file per item
+struct OrderFile {
+    ids: Vec<u64>,
+    next_id: u64,
+    version: u32,
+}
+
+/// Reads the order file: item IDs one per line plus `next N` and `version N`
+/// lines. git merges it with the union driver, so IDs may repeat (the first
+/// one counts) and there may be several `next` or `version` lines (the
+/// highest counts). Lines that are none of these, like stray conflict
+/// markers, are skipped.
+fn read_order(path: &Path) -> io::Result<OrderFile> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    // The layout didn't exist before format version 1
+    let mut order = OrderFile {
+        ids: Vec::new(),
+        next_id: 0,
+        version: 1,
+    };
+    for line in content.lines().map(str::trim) {
+        if let Some(n) = line
+            .strip_prefix("next ")
+            .and_then(|n| n.trim().parse().ok())
+        {
+            order.next_id = order.next_id.max(n);
+        } else if let Some(v) = line
+            .strip_prefix("version ")
+            .and_then(|v| v.trim().parse().ok())
+        {
+            order.version = order.version.max(v);
+        } else if let Ok(id) = line.parse() {
+            order.ids.push(id);
+        }
+    }
+    Ok(order)
+}
+
+/// Loads a backlog kept as one file per item. Items the order file doesn't
+/// mention (e.g. added on another branch) go last, oldest first.
+fn read_item_files(path: &Path) -> io::Result<Backlog> {
+    let mut files = Vec::new();
+    match fs::read_dir(items_dir(path)) {
+        Ok(entries) => {
+            for entry in entries {
+                let file = entry?.path();
+                if file.extension() == Some("json".as_ref()) {
+                    let content = fs::read_to_string(&file)?;
+                    files.push((file, content));
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let order = read_order(&order_path(path))?;
+    let pending = pending_upgrades(
+        &order_path(path),
+        order.version,
+        BACKLOG_UPGRADES,
+        BACKLOG_VERSION,
+    )?;
+    let mut next_id = order.next_id;
+    let mut items: Vec<BacklogItem> = Vec::new();
+    if pending.is_empty() {
+        for (file, content) in &files {
+            items.push(serde_json::from_str(content).map_err(|e| parse_error(file, &e))?);
+        }
+    } else {
+        // Upgrade steps work on a whole backlog, so assemble one
+        let mut values = Vec::new();
+        for (file, content) in &files {
+            values.push(serde_json::from_str::<Value>(content).map_err(|e| parse_error(file, &e))?);
+        }
+        let mut backlog = serde_json::Map::new();
+        backlog.insert("items".to_string(), Value::Array(values));
+        backlog.insert("next_id".to_string(), next_id.into());
+        for step in pending {
+            (step.apply)(&mut backlog);
+        }
+        next_id = backlog
+            .get("next_id")
+            .and_then(Value::as_u64)
+            .unwrap_or(next_id);
+        let upgraded = backlog.remove("items").unwrap_or_default();
+        items = serde_json::from_value(upgraded).map_err(|e| parse_error(&items_dir(path), &e))?;
+    }
+
+    let mut rank = HashMap::new();
+    for (i, id) in order.ids.into_iter().enumerate() {
+        rank.entry(id).or_insert(i);
+    }
+    items.sort_by_key(|item| {
+        (
+            rank.get(&item.id).copied().unwrap_or(usize::MAX),
+            item.created_at,
+            item.id,
+        )
+    });
+
+    let mut backlog = Backlog {
+        items,
+        next_id,
+        ..Backlog::default()
+    };
+    backlog.renumber_duplicates();
+    backlog.regroup();
+    Ok(backlog)
+}
+
+/// Saves a backlog as one file per item plus the order file. Only files whose
+/// content changed are rewritten, and files of removed items are deleted.
+fn write_item_files(path: &Path, backlog: &Backlog) -> io::Result<()> {
+    let dir = items_dir(path);
+    fs::create_dir_all(&dir)?;
+
+    let mut names = HashSet::new();
+    for item in &backlog.items {
+        let name = item_file_name(item);
+        let file = dir.join(&name);
+        let content = serde_json::to_string_pretty(item)? + "\n";
+        if fs::read_to_string(&file).ok().as_deref() != Some(content.as_str()) {
+            write_atomic(&file, &content)?;
+        }
+        names.insert(name);
+    }
+
+    let mut order = format!("version {}\nnext {}\n", BACKLOG_VERSION, backlog.next_id);
+    for item in &backlog.items {
+        order.push_str(&format!("{}\n", item.id));
+    }
+    write_atomic(&order_path(path), &order)?;
+    if let Some(parent) = path.parent() {
+        ensure_lines(&parent.join(".gitattributes"), &["order merge=union"])?;
+    }
+
+    for entry in fs::read_dir(&dir)? {
+        let file = entry?.path();
+        let stale = file.extension() == Some("json".as_ref())
+            && file
+                .file_name()
+                .is_some_and(|name| !names.contains(&*name.to_string_lossy()));
+        if stale {
+            fs::remove_file(&file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Archived items live in their own file next to backlog.json, in the same format
+fn archive_path(backlog_path: &Path) -> PathBuf {
+    backlog_path.with_file_name("archive.json")
+}
+
+/// How many changes `undo` can go back
+const HISTORY_DEPTH: usize = 50;
+
+/// The oldest changes are also dropped once the journal grows past this many bytes
+const HISTORY_SIZE: usize = 1 << 20;
+
+/// The undo/redo journal kept next to backlog.json. Each entry records only
+/// the items one change touched, so the journal stays small however long the
+/// backlog gets, and undo and redo can still tell when those items were
+/// changed some other way (e.g. by a `git pull`) in between.
+#[derive(Serialize, Deserialize)]
+struct History {
+    /// Format version, see `HISTORY_UPGRADES` (missing before items had a status)
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    undo: Vec<HistoryEntry>,
+    #[serde(default)]
+    redo: Vec<HistoryEntry>,
+}
+
+/// Format version of the undo journal written by this build. The entries hold
+/// items, so a change to their format needs a new step in `HISTORY_UPGRADES`
+/// as well as in `BACKLOG_UPGRADES`.
+const HISTORY_VERSION: u32 = 1;
+
+/// Upgrades for the undo journal, in order
+const HISTORY_UPGRADES: &[Upgrade] = &[Upgrade {
+    to: 1,
+    description: "replace the done flag of recorded items with a status",
+    apply: |history| {
+        for list in ["undo", "redo"] {
+            let entries = history.get_mut(list).and_then(Value::as_array_mut);
+            for entry in entries.into_iter().flatten() {
+                for file in ["backlog", "archive"] {
+                    for side in ["before", "after"] {
+                        let items = entry
+                            .get_mut(file)
+                            .and_then(|changes| changes.get_mut(side)?.as_array_mut());
+                        for item in items.into_iter().flatten().filter_map(Value::as_object_mut) {
+                            upgrade_item_status(item);
+                        }
+                    }
+                }
+            }
+        }
+    },
+}];
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            version: HISTORY_VERSION,
+            undo: Vec::new(),
+            redo: Vec::new(),
+        }
+    }
+}
+
+impl History {
+    /// Drops the oldest changes beyond `HISTORY_DEPTH` or `HISTORY_SIZE`,
+    /// always keeping the latest one
+    fn trim(&mut self) {
+        let excess = self.undo.len().saturating_sub(HISTORY_DEPTH);
+        self.undo.drain(..excess);
+
+        let sizes: Vec<usize> = self
+            .undo
+            .iter()
+            .map(|entry| serde_json::to_string(entry).map_or(0, |s| s.len()))
+            .collect();
+        let mut total: usize = sizes.iter().sum();
+        let mut dropped = 0;
+        while total > HISTORY_SIZE && dropped + 1 < sizes.len() {
+            total -= sizes[dropped];
+            dropped += 1;
+        }
+        self.undo.drain(..dropped);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryEntry {
+    /// What was done, e.g. "remove 4"
+    action: String,
+    at: DateTime<Utc>,
+    backlog: Changes,
+    /// What the change did to archive.json (`archive` and `restore` only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    archive: Option<Changes>,
+}
+
+/// How one change altered a backlog
+#[derive(Serialize, Deserialize)]
+struct Changes {
+    /// Items the change modified or removed, as they were before it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    before: Vec<Placed>,
+    /// Items the change modified or added, as they are after it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    after: Vec<Placed>,
+    /// `next_id` before and after the change
+    next_id: (u64, u64),
+    /// The IDs of all items in order before and after the change, only kept
+    /// when it also moved items it didn't modify (such as a parent's subtasks)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    order: Option<(Vec<u64>, Vec<u64>)>,
+}
+
+/// See `Changes::side`
+struct ChangeSide<'a> {
+    items: &'a [Placed],
+    next_id: u64,
+    order: Option<&'a Vec<u64>>,
+}
+
+impl ChangeSide<'_> {
+    fn contains(&self, id: u64) -> bool {
+        self.items.iter().any(|p| p.item.id == id)
+    }
+}
+
+/// An item together with its index in the backlog
+#[derive(Serialize, Deserialize)]
+struct Placed {
+    position: usize,
+    #[serde(flatten)]
+    item: BacklogItem,
+}
+
+impl Changes {
+    fn between(before: &Backlog, after: &Backlog) -> Self {
+        // Items missing from the other side or different there
+        let changed = |from: &Backlog, to: &Backlog| -> Vec<Placed> {
+            from.items
+                .iter()
+                .enumerate()
+                .filter(|(_, item)| {
+                    to.index_of(item.id)
+                        .is_none_or(|i| !same_item(item, &to.items[i]))
+                })
+                .map(|(position, item)| Placed {
+                    position,
+                    item: item.clone(),
+                })
+                .collect()
+        };
+        let changed_before = changed(before, after);
+        let changed_after = changed(after, before);
+
+        let ids = |backlog: &Backlog| -> Vec<u64> { backlog.items.iter().map(|i| i.id).collect() };
+        let untouched = |backlog: &Backlog, changed: &[Placed]| -> Vec<u64> {
+            ids(backlog)
+                .into_iter()
+                .filter(|id| !changed.iter().any(|p| p.item.id == *id))
+                .collect()
+        };
+        let order = (untouched(before, &changed_before) != untouched(after, &changed_after))
+            .then(|| (ids(before), ids(after)));
+
+        Changes {
+            before: changed_before,
+            after: changed_after,
+            next_id: (before.next_id, after.next_id),
+            order,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.before.is_empty()
+            && self.after.is_empty()
+            && self.order.is_none()
+            && self.next_id.0 == self.next_id.1
+    }
+
+    /// One side of the change: the touched items as they were before it (or
+    /// are after it), `next_id` then and the order of all items if it was kept
+    fn side(&self, after: bool) -> ChangeSide<'_> {
+        let (items, next_id) = if after {
+            (&self.after, self.next_id.1)
+        } else {
+            (&self.before, self.next_id.0)
+        };
+        ChangeSide {
+            items,
+            next_id,
+            order: self
+                .order
+                .as_ref()
+                .map(|(before, after_order)| if after { after_order } else { before }),
+        }
+    }
+
+    /// Whether the items this change touched are still as it left them (or,
+    /// for a redo, as they were before it), so it can be reverted cleanly
+    fn applies_to(&self, backlog: &Backlog, undo: bool) -> bool {
+        let (from, to) = (self.side(undo), self.side(!undo));
+        let in_place = from.items.iter().all(|p| {
+            backlog
+                .items
+                .get(p.position)
+                .is_some_and(|item| same_item(item, &p.item))
+        });
+        // Items the step brings back must not be there already
+        let absent = to
+            .items
+            .iter()
+            .filter(|p| !from.contains(p.item.id))
+            .all(|p| backlog.index_of(p.item.id).is_none());
+        let ordered = from
+            .order
+            .is_none_or(|order| backlog.items.iter().map(|i| i.id).eq(order.iter().copied()));
+        in_place && absent && ordered
+    }
+
+    /// Undoes (or redoes) the change on `backlog`. Items it didn't touch stay
+    /// as they are, even if they were changed since.
+    fn apply(&self, backlog: &Backlog, undo: bool) -> Backlog {
+        let (from, to) = (self.side(undo), self.side(!undo));
+        let mut items: Vec<BacklogItem> = backlog
+            .items
+            .iter()
+            .filter(|item| !from.contains(item.id) && !to.contains(item.id))
+            .cloned()
+            .collect();
+        match to.order {
+            Some(order) => {
+                let mut by_id: HashMap<u64, BacklogItem> =
+                    items.drain(..).map(|item| (item.id, item)).collect();
+                for p in to.items {
+                    by_id.insert(p.item.id, p.item.clone());
+                }
+                items = order.iter().filter_map(|id| by_id.remove(id)).collect();
+                // Anything added outside the journal since goes at the end
+                let mut rest: Vec<BacklogItem> = by_id.into_values().collect();
+                rest.sort_by_key(|item| item.created_at);
+                items.extend(rest);
+            }
+            None => {
+                for p in to.items {
+                    items.insert(p.position.min(items.len()), p.item.clone());
+                }
+            }
+        }
+        // Never hand out an ID that is still in use, even after a forced step
+        let next_id = items.iter().map(|i| i.id + 1).fold(to.next_id, u64::max);
+        let mut restored = Backlog {
+            version: backlog.version,
+            items,
+            next_id,
+        };
+        restored.regroup();
+        restored
+    }
+}
+
+fn history_path(backlog_path: &Path) -> PathBuf {
+    backlog_path.with_file_name("history.json")
+}
+
+/// The journal is only a convenience, so a missing or unreadable one starts empty
+fn load_history(backlog_path: &Path) -> History {
+    let path = history_path(backlog_path);
+    let Some(mut history) = fs::read_to_string(&path)
+        .ok()
+        .and_then(|content| serde_json::from_str::<Value>(&content).ok())
+    else {
+        return History::default();
+    };
+    // One from a newer version is set aside like an unreadable one
+    if upgrade(&mut history, &path, HISTORY_UPGRADES, HISTORY_VERSION).is_err() {
+        return History::default();
+    }
+    serde_json::from_value(history).unwrap_or_default()
+}
+
+fn save_history(backlog_path: &Path, history: &History) -> io::Result<()> {
+    let content = serde_json::to_string(history)?;
+    write_atomic(&history_path(backlog_path), &content)
+}
+
+/// Saves a changed backlog and records the change in the undo journal, which
+/// drops its redo entries and the oldest changes beyond `HISTORY_DEPTH` or
+/// `HISTORY_SIZE`. Must be called with the backlog locked.
+fn save_change(path: &Path, action: &str, before: Backlog, after: &Backlog) -> io::Result<()> {
+    save_backlog(path, after)?;
+    record_change(path, action, Changes::between(&before, after), None)
+}
+
+/// Records an already saved change in the undo journal. `archive` carries what
+/// the change did to archive.json, for the commands that move items there.
+fn record_change(
+    path: &Path,
+    action: &str,
+    backlog: Changes,
+    archive: Option<Changes>,
+) -> io::Result<()> {
+    let archive = archive.filter(|changes| !changes.is_empty());
+    if backlog.is_empty() && archive.is_none() {
+        return Ok(());
+    }
+
+    let mut history = load_history(path);
+    history.undo.push(HistoryEntry {
+        action: action.to_string(),
+        at: Utc::now(),
+        backlog,
+        archive,
+    });
+    history.trim();
+    history.redo.clear();
+    save_history(path, &history)
+}
+
+/// Reverts the latest change in the journal (or reapplies the latest undone
+/// one) under the backlog lock. Returns the action and the restored backlog.
+/// Refuses if the items the change touched were changed outside the journal
+/// since, unless `force`.
+fn step_history(path: &Path, redo: bool, force: bool) -> Result<(String, Backlog), Failure> {
+    let _lock = lock_file(path)?;
+    let current = load_backlog(&path.to_path_buf())?;
+    let mut history = load_history(path);
+
+    let (from, to) = if redo {
+        (&mut history.redo, &mut history.undo)
+    } else {
+        (&mut history.undo, &mut history.redo)
+    };
+    let Some(entry) = from.pop() else {
+        return Err(format!("Nothing to {}", if redo { "redo" } else { "undo" }).into());
+    };
+    let archive_file = archive_path(path);
+    let archive = match &entry.archive {
+        Some(_) => Some(load_backlog(&archive_file)?),
+        None => None,
+    };
+    let untouched = entry.backlog.applies_to(&current, !redo)
+        && entry
+            .archive
+            .iter()
+            .zip(&archive)
+            .all(|(changes, archive)| changes.applies_to(archive, !redo));
+    if !force && !untouched {
+        return Err(format!(
+            "The backlog was changed outside the undo history since '{}'; use --force to {} it anyway",
+            entry.action,
+            if redo { "redo" } else { "undo" }
+        )
+        .into());
+    }
+
+    let restored = entry.backlog.apply(&current, !redo);
+    let archive = entry
+        .archive
+        .iter()
+        .zip(archive)
+        .map(|(changes, archive)| (changes.apply(&archive, !redo), archive.items.len()))
+        .next();
+    let action = entry.action.clone();
+    to.push(entry);
+    match archive {
+        // Write whichever file gains items first so a failure can't lose any
+        Some((restored_archive, count)) if restored_archive.items.len() > count => {
+            save_backlog(&archive_file, &restored_archive)?;
+            save_backlog(path, &restored)?;
+        }
+        Some((restored_archive, _)) => {
+            save_backlog(path, &restored)?;
+            save_backlog(&archive_file, &restored_archive)?;
+        }
+        None => save_backlog(path, &restored)?,
+    }
+    save_history(path, &history)?;
+    Ok((action, restored))
+}
+
+/// The command line that made a change, as recorded in the undo journal
+fn command_line() -> String {
+    std::env::args().skip(1).collect::<Vec<_>>().join(" ")
+}
+
+/// Runs a load-modify-save cycle on the current repo's backlog while holding
+/// its lock. Exits with a message if any step fails or `f` rejects the change,
+/// in which case nothing is written.
+fn modify_backlog<T>(f: impl FnOnce(&mut Backlog) -> Result<T, Failure>) -> (Backlog, T) {
+    let Some(backlog_path) = get_repo_backlog_path() else {
+        fail(EXIT_NOT_A_REPO, "Not in a git repository");
+    };
+
+    let _lock = lock_backlog_or_exit(&backlog_path);
+    let mut backlog = load_backlog_or_exit(&backlog_path);
+    let before = backlog.clone();
+    let result = match f(&mut backlog) {
+        Ok(result) => result,
+        Err(failure) => fail(failure.code, failure.message),
+    };
+    if let Err(e) = save_change(&backlog_path, &command_line(), before, &backlog) {
+        fail(EXIT_FAILURE, format!("Failed to save backlog: {}", e));
+    }
+    (backlog, result)
+}
+
+/// `modify_backlog` for commands acting on one item, passing `f` its index
+fn modify_item<T>(
+    item: ItemRef,
+    f: impl FnOnce(&mut Backlog, usize) -> Result<T, Failure>,
+) -> (Backlog, T) {
+    modify_backlog(|backlog| {
+        let idx = backlog
+            .resolve(item)
+            .ok_or_else(|| Failure::no_such_item(item))?;
+        f(backlog, idx)
+    })
+}
+
+/// Splits a file containing git conflict markers into its "ours" and "theirs"
+/// versions, keeping unconflicted lines in both. Returns `None` without markers.
+fn split_conflict_sides(content: &str) -> Option<(String, String)> {
+    #[derive(PartialEq)]
+    enum Side {
+        Both,
+        Ours,
+        Base,
+        Theirs,
+    }
+
+    if !content.lines().any(|l| l.starts_with("<<<<<<<")) {
+        return None;
+    }
+
+    let mut ours = String::new();
+    let mut theirs = String::new();
+    let mut side = Side::Both;
+    for line in content.lines() {
+        if line.starts_with("<<<<<<<") {
+            side = Side::Ours;
+        } else if line.starts_with("|||||||") && side == Side::Ours {
+            // diff3 style: skip the common ancestor section
+            side = Side::Base;
+        } else if line.starts_with("=======") && side != Side::Both {
+            side = Side::Theirs;
+        } else if line.starts_with(">>>>>>>") {
+            side = Side::Both;
+        } else {
+            match side {
+                Side::Both => {
+                    ours.push_str(line);
+                    ours.push('\n');
+                    theirs.push_str(line);
+                    theirs.push('\n');
+                }
+                Side::Ours => {
+                    ours.push_str(line);
+                    ours.push('\n');
+                }
+                Side::Theirs => {
+                    theirs.push_str(line);
+                    theirs.push('\n');
+                }
+                Side::Base => {}
+            }
+        }
+    }
+    Some((ours, theirs))
+}
+
+/// Pulls every well-formed item object out of text that is not valid JSON as a whole
+fn scan_items(text: &str) -> Vec<BacklogItem> {
+    let mut items = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = text[pos..].find('{') {
+        let start = pos + offset;
+        let mut stream = serde_json::Deserializer::from_str(&text[start..]).into_iter::<Value>();
+        // Items may come from a file written before items had a status
+        let item = match stream.next() {
+            Some(Ok(Value::Object(mut item))) => {
+                upgrade_item_status(&mut item);
+                serde_json::from_value::<BacklogItem>(Value::Object(item)).ok()
+            }
+            _ => None,
+        };
+        match item {
+            Some(item) => {
+                items.push(item);
+                pos = start + stream.byte_offset();
+            }
+            None => pos = start + 1,
+        }
+    }
+    items
+}
+
+/// Finds the `next_id` counter in text that may not parse as a whole
+fn scan_next_id(text: &str) -> u64 {
+    let Some(pos) = text.find("\"next_id\"") else {
+        return 0;
+    };
+    let rest = text[pos + "\"next_id\"".len()..].trim_start();
+    let digits: String = rest
+        .strip_prefix(':')
+        .unwrap_or(rest)
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().unwrap_or(0)
+}
+
+/// Recovers as much of a broken backlog file as possible. Both sides of any
+/// merge conflict are kept; exact duplicates are dropped and clashing IDs are
+/// reassigned so nothing is silently merged away.
+fn salvage_backlog(content: &str) -> Backlog {
+    let sides = match split_conflict_sides(content) {
+        Some((ours, theirs)) => vec![ours, theirs],
+        None => vec![content.to_string()],
+    };
+
+    let mut backlog = Backlog::default();
+    for side in &sides {
+        let (items, next_id) = match serde_json::from_str::<Backlog>(side) {
+            Ok(parsed) => (parsed.items, parsed.next_id),
+            Err(_) => (scan_items(side), scan_next_id(side)),
+        };
+        backlog.next_id = backlog.next_id.max(next_id);
+        for item in items {
+            let duplicate = backlog.items.iter().any(|existing| {
+                existing.id == item.id
+                    && existing.description == item.description
+                    && existing.created_at == item.created_at
+                    && existing.status == item.status
+            });
+            if !duplicate {
+                backlog.items.push(item);
+            }
+        }
+    }
+
+    backlog.renumber_duplicates();
+    backlog
+}
+
+/// What `backlog doctor` found in one backlog file
+#[derive(Serialize)]
+struct FileCheck {
+    file: String,
+    /// Items in the file, or that could be recovered from it when it's broken
+    items: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Whether `doctor --fix` can write the recovered items back
+    repairable: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    conflicted: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    recovered: Vec<BacklogItem>,
+    /// Where the broken file was copied before it was repaired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup: Option<String>,
+}
+
+impl FileCheck {
+    fn healthy(file: &Path, items: usize) -> Self {
+        FileCheck {
+            file: file.display().to_string(),
+            items,
+            error: None,
+            repairable: false,
+            conflicted: false,
+            recovered: Vec::new(),
+            backup: None,
+        }
+    }
+
+    fn print(&self) {
+        let Some(error) = &self.error else {
+            outln!("{} is healthy ({} item(s)).", self.file, self.items);
+            return;
+        };
+        outln!("{}", error);
+        if !self.repairable {
+            outln!("Fix or delete that file by hand.");
+            return;
+        }
+        if self.conflicted {
+            outln!("Found merge conflict markers; keeping items from both sides.");
+        }
+        outln!("\nRecovered {} item(s):", self.items);
+        for (i, item) in self.recovered.iter().enumerate() {
+            outln!("{}", format_item(i + 1, item));
+        }
+        outln!();
+        match &self.backup {
+            Some(backup) => outln!("Repaired. The original file was saved to {}", backup),
+            None => outln!("Run 'backlog doctor --fix' to write these items back."),
+        }
+    }
+}
+
+/// Checks a single-file backlog (backlog.json or archive.json) for `backlog
+/// doctor`, recovering what it can from a broken one and, with `fix`, writing
+/// that back. The caller holds the backlog lock.
+fn doctor_file(path: &Path, fix: bool) -> FileCheck {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            fail(
+                EXIT_FAILURE,
+                format!("Failed to read {}: {}", path.display(), e),
+            );
+        }
+    };
+
+    let err = match parse_backlog(&content, path) {
+        Ok(backlog) => return FileCheck::healthy(path, backlog.items.len()),
+        // Not broken, just newer; salvaging it would lose data
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => fail(EXIT_FAILURE, e),
+        Err(e) => e,
+    };
+
+    let backlog = salvage_backlog(&content);
+    let mut check = FileCheck {
+        items: backlog.items.len(),
+        error: Some(err.to_string()),
+        repairable: true,
+        conflicted: split_conflict_sides(&content).is_some(),
+        recovered: backlog.items.clone(),
+        ..FileCheck::healthy(path, 0)
+    };
+    if !fix {
+        return check;
+    }
+
+    let bak = match keep_backup(path, &content) {
+        Ok(bak) => bak,
+        Err(e) => {
+            fail(
+                EXIT_FAILURE,
+                format!("Failed to back up {}: {}", path.display(), e),
+            );
+        }
+    };
+    if let Err(e) = save_backlog(path, &backlog) {
+        fail(EXIT_FAILURE, format!("Failed to save backlog: {}", e));
+    }
+    check.backup = Some(bak.display().to_string());
+    check
+}
+
+fn same_item(a: &BacklogItem, b: &BacklogItem) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+/// IDs of a backlog's items that also exist in `base`, in the backlog's order
+fn shared_order(backlog: &Backlog, base: &Backlog) -> Vec<u64> {
+    backlog
+        .items
+        .iter()
+        .map(|i| i.id)
+        .filter(|&id| base.index_of(id).is_some())
+        .collect()
+}
+
+/// Three-way merge of two backlogs that both started out as `base`, item by
+/// item: items added on either side are kept, an item changed on both sides
+/// takes the most recently updated version, and an item deleted on either side
+/// stays deleted. Items both sides added under the same ID are kept apart by
+/// giving theirs a new ID. The order comes from whichever side reordered items,
+/// preferring ours, with the other side's new items slotted in after their
+/// predecessors.
+fn merge_backlogs(base: &Backlog, ours: &Backlog, theirs: &Backlog) -> Backlog {
+    let mut merged = Backlog {
+        next_id: base.next_id.max(ours.next_id).max(theirs.next_id),
+        ..Backlog::default()
+    };
+    for item in base.items.iter().chain(&ours.items).chain(&theirs.items) {
+        merged.next_id = merged.next_id.max(item.id + 1);
+    }
+
+    let mut theirs = theirs.clone();
+    let mut renumbered = HashMap::new();
+    for item in &theirs.items {
+        let clash = base.index_of(item.id).is_none()
+            && ours
+                .index_of(item.id)
+                .is_some_and(|i| ours.items[i].created_at != item.created_at);
+        if clash {
+            renumbered.insert(item.id, merged.allocate_id());
+        }
+    }
+    // References on their side all mean their items, so they move along
+    let renumber = |id: &mut u64| {
+        if let Some(&new) = renumbered.get(id) {
+            *id = new;
+        }
+    };
+    for item in &mut theirs.items {
+        renumber(&mut item.id);
+        if let Some(parent) = item.parent.as_mut() {
+            renumber(parent);
+        }
+        for blocker in &mut item.blocked_by {
+            renumber(blocker);
+        }
+    }
+
+    let (primary, secondary) = if shared_order(ours, base) != shared_order(base, ours)
+        || shared_order(&theirs, base) == shared_order(base, &theirs)
+    {
+        (ours, &theirs)
+    } else {
+        (&theirs, ours)
+    };
+    let mut order: Vec<u64> = primary.items.iter().map(|i| i.id).collect();
+    for (pos, item) in secondary.items.iter().enumerate() {
+        if order.contains(&item.id) {
+            continue;
+        }
+        let at = secondary.items[..pos]
+            .iter()
+            .rev()
+            .find_map(|prev| order.iter().position(|&id| id == prev.id))
+            .map_or(0, |p| p + 1);
+        order.insert(at, item.id);
+    }
+
+    let find = |backlog: &Backlog, id: u64| backlog.index_of(id).map(|i| backlog.items[i].clone());
+    for id in order {
+        let item = match (find(base, id), find(ours, id), find(&theirs, id)) {
+            (Some(b), Some(o), Some(t)) => {
+                if same_item(&o, &b) || (!same_item(&t, &b) && t.last_updated() > o.last_updated())
+                {
+                    t
+                } else {
+                    o
+                }
+            }
+            (Some(_), _, _) => continue,
+            (None, Some(o), Some(t)) => {
+                if t.last_updated() > o.last_updated() {
+                    t
+                } else {
+                    o
+                }
+            }
+            (None, Some(item), None) | (None, None, Some(item)) => item,
+            (None, None, None) => continue,
+        };
+        merged.items.push(item);
+    }
+
+    merged.prune_blockers();
+    merged.regroup();
+    merged
+}
+
+/// Runs git in `repo` and returns its trimmed output, or its error message
+fn git(repo: &Path, args: &[&str]) -> io::Result<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// IDs of the items a commit message closes, e.g. 12 and 14 for
+/// "Fixes backlog#12, backlog#14" (also "closes" and "resolves")
+fn closing_refs(message: &str) -> Vec<u64> {
+    let pattern = Regex::new(
+        r"(?i)\b(?:fix(?:e[sd])?|close[sd]?|resolve[sd]?):?\s+(backlog#\d+(?:(?:\s*,\s*|\s+and\s+)backlog#\d+)*)",
+    )
+    .expect("closing pattern is valid");
+    let mut ids = Vec::new();
+    for caps in pattern.captures_iter(message) {
+        for id in caps[1].split("backlog#").filter_map(|n| {
+            n.trim_end_matches(|c: char| !c.is_ascii_digit())
+                .parse()
+                .ok()
+        }) {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+    ids
+}
+
+/// Branch name for an item, e.g. `backlog/12-fix-login-redirect`: the prefix,
+/// the item's ID and the first few words of its description
+fn branch_name(prefix: &str, item: &BacklogItem) -> String {
+    let mut slug = String::new();
+    for word in item
+        .description
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        if slug.len() + word.len() > 40 {
+            break;
+        }
+        slug.push('-');
+        slug.push_str(word);
+    }
+    format!("{}{}{}", prefix, item.id, slug)
+}
+
+/// Script installed as a git hook; it does nothing if backlog isn't installed
+fn hook_script(hook: HookName) -> String {
+    format!(
+        "#!/bin/sh\n# Installed by 'backlog hook install'\ncommand -v backlog >/dev/null 2>&1 || exit 0\nexec backlog hook run {} \"$@\"\n",
+        hook.file_name()
+    )
+}
+
+/// A TODO, FIXME or HACK comment found by `backlog scan`
+#[derive(Serialize)]
+struct Marker {
+    /// Path relative to the repo root
+    file: String,
+    line: usize,
+    /// The marker word, lowercased, which becomes the item's tag
+    kind: String,
+    /// The rest of the comment
+    text: String,
+}
+
+/// Matches a marker comment. The marker has to follow a comment leader, so
+/// identifiers and strings that merely contain "todo" don't count.
+fn marker_pattern() -> Regex {
+    Regex::new(
+        r"(?://+[!/]?|#+|/\*+!?|^\s*\*|--|;+|<!--)\s*\b(TODO|FIXME|HACK)\b(?:\([^)]*\))?:?\s*(.*)",
+    )
+    .expect("marker pattern is valid")
+}
+
+/// The marker word (lowercased) and the rest of the comment, if `line` has a
+/// marker comment
+fn find_marker(pattern: &Regex, line: &str) -> Option<(String, String)> {
+    let caps = pattern.captures(line)?;
+    let text = caps[2]
+        .trim_end()
+        .trim_end_matches("*/")
+        .trim_end_matches("-->");
+    Some((caps[1].to_lowercase(), text.trim().to_string()))
+}
+
+/// Finds marker comments in every file git tracks or would track in `repo`,
+/// so anything .gitignore excludes is skipped. Binary files are skipped too.
+fn scan_markers(repo: &Path) -> io::Result<Vec<Marker>> {
+    let pattern = marker_pattern();
+
+    let files = git(
+        repo,
+        &[
+            "ls-files",
+            "-z",
+            "--cached",
+            "--others",
+            "--exclude-standard",
+        ],
+    )?;
+    let mut markers = Vec::new();
+    for file in files.split('\0').filter(|f| !f.is_empty()) {
+        if file.starts_with(".backlog/") || file.starts_with(".todo/") {
+            continue;
+        }
+        let Ok(content) = fs::read_to_string(repo.join(file)) else {
+            continue;
+        };
+        for (i, line) in content.lines().enumerate() {
+            if let Some((kind, text)) = find_marker(&pattern, line) {
+                markers.push(Marker {
+                    file: file.to_string(),
+                    line: i + 1,
+                    kind,
+                    text,
+                });
+            }
+        }
+    }
+    Ok(markers)
+}
+
+/// What a scan changes in a backlog
+struct ScanResult {
+    /// Indices of markers that no item tracks yet
+    new: Vec<usize>,
+    /// IDs of items whose comment disappeared since the last scan
+    gone: Vec<u64>,
+}
+
+/// Matches scanned markers to the items imported from them earlier, by file
+/// and comment text so that moved lines are still recognized. Matched items
+/// get their line number updated and items whose comment is gone get flagged.
+/// Archived items count as tracked, so finished ones aren't imported again.
+fn reconcile_markers(backlog: &mut Backlog, archive: &Backlog, markers: &[Marker]) -> ScanResult {
+    let mut claimed = vec![false; markers.len()];
+    let mut claim = |source: &Source| {
+        let i = (0..markers.len()).find(|&i| {
+            !claimed[i] && markers[i].file == source.file && markers[i].text == source.text
+        })?;
+        claimed[i] = true;
+        Some(i)
+    };
+
+    let mut gone = Vec::new();
+    for item in &mut backlog.items {
+        let Some(source) = item.source.as_mut() else {
+            continue;
+        };
+        match claim(source) {
+            Some(i) => {
+                source.line = markers[i].line;
+                source.missing = false;
+            }
+            None if !source.missing => {
+                source.missing = true;
+                gone.push(item.id);
+            }
+            None => {}
+        }
+    }
+    for source in archive.items.iter().filter_map(|i| i.source.as_ref()) {
+        claim(source);
+    }
+
+    ScanResult {
+        new: (0..markers.len()).filter(|&i| !claimed[i]).collect(),
+        gone,
+    }
+}
+
+/// Asks a yes/no question on the terminal; anything but "y" means no
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = stdout().flush();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
+
+fn load_global_index() -> io::Result<GlobalIndex> {
+    let path = get_global_index_path();
+    if path.exists() {
+        let content = fs::read_to_string(&path)?;
+        parse_versioned(&content, &path, INDEX_UPGRADES, INDEX_VERSION)
+    } else {
+        Ok(GlobalIndex::default())
+    }
+}
+
+/// Loads the global index for a CLI command, exiting with a message if it can't be read
+fn load_global_index_or_exit() -> GlobalIndex {
+    match load_global_index() {
+        Ok(index) => index,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => fail(EXIT_CORRUPT, e),
+        Err(e) => fail(EXIT_FAILURE, e),
+    }
+}
+
+fn save_global_index(index: &GlobalIndex) -> std::io::Result<()> {
+    let content = serde_json::to_string_pretty(index)?;
+    write_atomic(&get_global_index_path(), &content)
+}
+
+/// Normalizes a tag typed by the user: no leading `#`, lowercase
+fn normalize_tag(tag: &str) -> String {
+    tag.trim_start_matches('#').to_lowercase()
+}
+
+/// Splits `#tag` tokens out of a description, returning the remaining text and
+/// the tags. Purely numeric tokens like `#12` are left alone as issue references.
+fn extract_tags(text: &str) -> (String, Vec<String>) {
+    let mut words = Vec::new();
+    let mut tags = Vec::new();
+    for word in text.split_whitespace() {
+        let is_tag = word.strip_prefix('#').is_some_and(|rest| {
+            !rest.is_empty()
+                && rest
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '/'))
+                && !rest.chars().all(|c| c.is_ascii_digit())
+        });
+        if is_tag {
+            let tag = normalize_tag(word);
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        } else {
+            words.push(word);
+        }
+    }
+    (words.join(" "), tags)
+}
+
+/// Parses a due date relative to `today`: `2026-11-01`, `today`, `tomorrow`,
+/// a weekday name (its next occurrence), or an offset like `+3d`, `+2w`, `+1m`
+fn parse_due(input: &str, today: NaiveDate) -> Result<NaiveDate, String> {
+    let s = input.trim().to_lowercase();
+    let invalid = || {
+        format!(
+            "invalid due date '{}': use YYYY-MM-DD, today, tomorrow, a weekday, or +Nd/+Nw/+Nm",
+            input
+        )
+    };
+
+    if let Ok(date) = NaiveDate::parse_from_str(&s, "%Y-%m-%d") {
+        return Ok(date);
+    }
+    match s.as_str() {
+        "today" => return Ok(today),
+        "tomorrow" => return Ok(today + Days::new(1)),
+        _ => {}
+    }
+    if let Some(offset) = s.strip_prefix('+') {
+        let (count, unit) = match offset.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => (&offset[..i], c),
+            _ => (offset, 'd'),
+        };
+        let n: u32 = count.parse().map_err(|_| invalid())?;
+        let date = match unit {
+            'd' => today.checked_add_days(Days::new(n.into())),
+            'w' => today.checked_add_days(Days::new(u64::from(n) * 7)),
+            'm' => today.checked_add_months(Months::new(n)),
+            _ => None,
+        };
+        return date.ok_or_else(invalid);
+    }
+    if let Ok(weekday) = s.parse::<Weekday>() {
+        let mut date = today + Days::new(1);
+        while date.weekday() != weekday {
+            date = date + Days::new(1);
+        }
+        return Ok(date);
+    }
+    Err(invalid())
+}
+
+/// Parses an age like `14d`, `2w` or `1m` into the moment that long before `now`
+fn parse_age(input: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let s = input.trim().to_lowercase();
+    let invalid = || format!("invalid age '{}': use Nd, Nw or Nm, e.g. 14d", input);
+
+    let (count, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s.as_str(), 'd'),
+    };
+    let n: u32 = count.parse().map_err(|_| invalid())?;
+    let cutoff = match unit {
+        'd' => now.checked_sub_days(Days::new(n.into())),
+        'w' => now.checked_sub_days(Days::new(u64::from(n) * 7)),
+        'm' => now.checked_sub_months(Months::new(n)),
+        _ => None,
+    };
+    cutoff.ok_or_else(invalid)
+}
+
+/// Clap value parser for `--older-than`, relative to now
+fn parse_age_arg(input: &str) -> Result<DateTime<Utc>, String> {
+    parse_age(input, Utc::now())
+}
+
+/// Clap value parser for due dates, relative to the local date
+fn parse_due_arg(input: &str) -> Result<NaiveDate, String> {
+    parse_due(input, Local::now().date_naive())
+}
+
+/// Opens `text` in $VISUAL/$EDITOR (falling back to vi) and returns the saved contents
+fn edit_in_editor(text: &str) -> io::Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // Allow editors with arguments, e.g. "code --wait"
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+
+    let path = std::env::temp_dir().join(format!("backlog-{}.md", std::process::id()));
+    fs::write(&path, text)?;
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(&path)
+        .status();
+    let result = match status {
+        Ok(status) if status.success() => fs::read_to_string(&path),
+        Ok(status) => Err(io::Error::other(format!(
+            "{} exited with {}",
+            program, status
+        ))),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("failed to run {}: {}", program, e),
+        )),
+    };
+    let _ = fs::remove_file(&path);
+    result
+}
+
+/// Normalizes edited notes: surrounding blank lines trimmed, empty means none
+fn clean_notes(text: &str) -> Option<String> {
+    let text = text.trim_matches(|c| c == '\n' || c == '\r').trim_end();
+    if text.trim().is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+/// Lines describing an item in full, shared by `backlog show` and the TUI detail pane
+fn item_details(backlog: &Backlog, idx: usize) -> Vec<String> {
+    let item = &backlog.items[idx];
+    let mut lines = vec![
+        item.description.clone(),
+        String::new(),
+        format!("ID:       @{}", item.id),
+        format!("Status:   {}", item.status),
+        format!(
+            "Created:  {}",
+            item.created_at
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M")
+        ),
+    ];
+    if let Some(at) = item.completed_at {
+        lines.push(format!(
+            "Done:     {} ({})",
+            at.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            time_ago(at)
+        ));
+    }
+    if let Some(at) = item.updated_at {
+        lines.push(format!(
+            "Updated:  {} ({})",
+            at.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            time_ago(at)
+        ));
+    }
+    if let Some(p) = item.priority {
+        lines.push(format!("Priority: {}", p.label()));
+    }
+    if let Some(due) = item.due {
+        let overdue = if item.is_overdue() { " (overdue)" } else { "" };
+        lines.push(format!("Due:      {}{}", due, overdue));
+    }
+    if !item.tags.is_empty() {
+        let tags: Vec<String> = item.tags.iter().map(|t| format!("#{}", t)).collect();
+        lines.push(format!("Tags:     {}", tags.join(" ")));
+    }
+    if let Some(source) = &item.source {
+        let missing = if source.missing {
+            " (comment no longer there)"
+        } else {
+            ""
+        };
+        lines.push(format!(
+            "Source:   {}:{}{}",
+            source.file, source.line, missing
+        ));
+    }
+    if let Some(sha) = &item.closed_by {
+        lines.push(format!("Commit:   {}", sha));
+    }
+    if let Some(at) = item.started_at {
+        lines.push(format!(
+            "Started:  {} ({})",
+            at.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            time_ago(at)
+        ));
+    }
+    if let Some(branch) = &item.branch {
+        lines.push(format!("Branch:   {}", branch));
+    }
+    for (i, blocker) in backlog.pending_blockers(idx).iter().enumerate() {
+        let label = if i == 0 { "Blocked:  " } else { "          " };
+        lines.push(format!("{}@{} {}", label, blocker.id, blocker.description));
+    }
+    if let Some(notes) = &item.notes {
+        lines.push(String::new());
+        lines.extend(notes.lines().map(String::from));
+    }
+    lines
+}
+
+/// Describes how long ago `at` was, e.g. "3d ago"
+fn time_ago(at: DateTime<Utc>) -> String {
+    let elapsed = Utc::now().signed_duration_since(at);
+    if elapsed.num_minutes() < 1 {
+        "just now".to_string()
+    } else if elapsed.num_hours() < 1 {
+        format!("{}m ago", elapsed.num_minutes())
+    } else if elapsed.num_days() < 1 {
+        format!("{}h ago", elapsed.num_hours())
+    } else {
+        format!("{}d ago", elapsed.num_days())
+    }
+}
+
+/// Wraps text in ANSI red when stdout is a terminal
+fn red(text: &str) -> String {
+    if stdout().is_terminal() {
+        format!("\x1b[31m{}\x1b[0m", text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Bold green on a terminal, plain otherwise
+fn green(text: &str) -> String {
+    if stdout().is_terminal() {
+        format!("\x1b[1;32m{}\x1b[0m", text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Formats an item for plain-text listings, e.g. `3. [ ] @7 (high) Fix login #bug (due 2026-11-01)`
+fn format_item(position: usize, item: &BacklogItem) -> String {
+    format_tree_item(position, item, 0, None)
+}
+
+/// Like `format_item`, indented by `depth` and followed by subtask progress like `[2/5]`
+fn format_tree_item(
+    position: usize,
+    item: &BacklogItem,
+    depth: usize,
+    progress: Option<(usize, usize)>,
+) -> String {
+    let indent = "  ".repeat(depth);
+    let status = item.checkbox();
+    let priority = match item.priority {
+        Some(p) => format!("({}) ", p.label()),
+        None => String::new(),
+    };
+    let tags: String = item.tags.iter().map(|t| format!(" #{}", t)).collect();
+    let due = match item.due {
+        Some(due) if item.is_overdue() => format!(" (overdue, due {})", due),
+        Some(due) => format!(" (due {})", due),
+        None => String::new(),
+    };
+    let progress = match progress {
+        Some((done, total)) => format!(" [{}/{}]", done, total),
+        None => String::new(),
+    };
+    let gone = if item.comment_gone() {
+        " (comment gone)"
+    } else {
+        ""
+    };
+    let completed = match item.completed_at {
+        Some(at) if item.is_done() => format!(" (done {})", time_ago(at)),
+        _ => String::new(),
+    };
+    format!(
+        "{}. {}{} @{} {}{}{}{}{}{}{}",
+        position,
+        indent,
+        status,
+        item.id,
+        priority,
+        item.description,
+        progress,
+        tags,
+        due,
+        gone,
+        completed
+    )
+}
+
+fn register_repo(repo_path: &str) {
+    let Ok(_lock) = lock_file(&get_global_index_path()) else {
+        return;
+    };
+    // Never overwrite an index we couldn't read
+    let Ok(mut index) = load_global_index() else {
+        return;
+    };
+    if !index.repos.contains(&repo_path.to_string()) {
+        index.repos.push(repo_path.to_string());
+        let _ = save_global_index(&index);
+    }
+}
+
+/// The `--format` chosen on the command line
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+fn output_format() -> Format {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+// Exit codes are part of the scripting interface and must stay stable. Besides
+// these, 0 means success and 2 is an invalid command line (reported by clap).
+const EXIT_FAILURE: i32 = 1;
+const EXIT_NOT_A_REPO: i32 = 3;
+const EXIT_NO_SUCH_ITEM: i32 = 4;
+const EXIT_INVALID: i32 = 5;
+const EXIT_CORRUPT: i32 = 6;
+
+/// Reports an error and exits with `code`. In the JSON formats the error is
+/// written to stderr as `{"error": ..., "code": ...}`.
+fn fail(code: i32, message: impl fmt::Display) -> ! {
+    warn(code, message);
+    std::process::exit(code)
+}
+
+/// Reports an error the command carries on after, such as one repo's backlog
+/// failing to load during `list --all`, in the same shape as `fail`
+fn warn(code: i32, message: impl fmt::Display) {
+    match output_format() {
+        Format::Plain => eprintln!("{}", message),
+        Format::Json | Format::Ndjson => eprintln!(
+            "{}",
+            serde_json::json!({ "error": message.to_string(), "code": code })
+        ),
+    }
+}
+
+/// Reports a repo whose backlog couldn't be loaded by a command that goes
+/// through all repos
+fn warn_repo(repo: &str, e: io::Error) {
+    let failure = Failure::from(e);
+    warn(failure.code, format!("{}: {}", repo, failure.message));
+}
+
+/// Ends the command after writing to stdout failed. A reader that went away
+/// early (`backlog list | head -1`) is not an error.
+fn output_failed(e: io::Error) -> ! {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        std::process::exit(0)
+    }
+    fail(EXIT_FAILURE, format!("Failed to write output: {}", e))
+}
+
+/// Why a command refused to change the backlog
+#[derive(Debug)]
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+impl Failure {
+    fn no_such_item(item: impl fmt::Display) -> Self {
+        Failure {
+            code: EXIT_NO_SUCH_ITEM,
+            message: format!("No such item: {}", item),
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Self {
+        let code = if e.kind() == io::ErrorKind::InvalidData {
+            EXIT_CORRUPT
+        } else {
+            EXIT_FAILURE
+        };
+        Failure {
+            code,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure {
+            code: EXIT_INVALID,
+            message,
+        }
+    }
+}
+
+/// An item as printed by `--format json`/`ndjson`. Unset optional fields are
+/// left out, as they are in backlog.json.
+#[derive(Serialize)]
+struct ItemRecord<'a> {
+    repo: &'a str,
+    position: usize,
+    #[serde(flatten)]
+    item: &'a BacklogItem,
+    /// Kept for scripts written before items had a status
+    done: bool,
+    blocked: bool,
+    overdue: bool,
+}
+
+/// What `backlog scan` did, or would do given `--yes`: import a new comment as
+/// an item, or flag an item whose comment is gone. Pending imports carry the
+/// comment's `file`, `line`, `kind` and `text`; everything else the item.
+#[derive(Serialize)]
+struct ScanRecord<'a> {
+    action: &'static str,
+    pending: bool,
+    #[serde(flatten)]
+    marker: Option<&'a Marker>,
+    #[serde(flatten)]
+    item: Option<ItemRecord<'a>>,
+}
+
+/// The current repo's root as it is recorded in the global index
+fn current_repo() -> String {
+    get_repo_root()
+        .map(|root| root.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Builds the case-insensitive matcher used by `search` and the TUI's `/`.
+/// The query is taken literally unless `regex` is set.
+fn search_pattern(query: &str, regex: bool) -> Result<Regex, regex::Error> {
+    let pattern = if regex {
+        query.to_string()
+    } else {
+        regex::escape(query)
+    };
+    RegexBuilder::new(&pattern).case_insensitive(true).build()
+}
+
+/// Prints a command's single result: as a JSON record (or `null`), or in
+/// plain format by calling `plain`
+fn emit_one(record: Option<ItemRecord>, plain: impl FnOnce()) {
+    match output_format() {
+        Format::Plain => plain(),
+        Format::Json => outln!(
+            "{}",
+            serde_json::to_string_pretty(&record).unwrap_or_default()
+        ),
+        Format::Ndjson => {
+            if let Some(record) = record {
+                outln!("{}", serde_json::to_string(&record).unwrap_or_default());
+            }
+        }
+    }
+}
+
+/// Prints a command's list of results: as a JSON array or one record per
+/// line, or in plain format by calling `plain`
+fn emit_list<T: Serialize>(records: &[T], plain: impl FnOnce()) {
+    match output_format() {
+        Format::Plain => plain(),
+        Format::Json => outln!(
+            "{}",
+            serde_json::to_string_pretty(records).unwrap_or_default()
+        ),
+        Format::Ndjson => {
+            for record in records {
+                outln!("{}", serde_json::to_string(record).unwrap_or_default());
+            }
+        }
+    }
+}
+
+/// Prints the outcome of a command that reports on files rather than items: as
+/// a JSON object (on one line with `ndjson`), or in plain format by calling `plain`
+fn emit_report(report: Value, plain: impl FnOnce()) {
+    match output_format() {
+        Format::Plain => plain(),
+        Format::Json => outln!(
+            "{}",
+            serde_json::to_string_pretty(&report).unwrap_or_default()
+        ),
+        Format::Ndjson => outln!("{}", report),
+    }
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Normal,
+    Edit,
+    Add,
+    ConfirmDelete,
+    Search,
+    MoveToRepo,
+}
+
+struct App {
+    backlog: Backlog,
+    backlog_path: PathBuf,
+    selected: usize,
+    scroll_offset: usize,
+    mode: Mode,
+    edit_buffer: String,
+    edit_cursor: usize,
+    output: Option<String>,
+    pending_d: bool,               // for dd delete
+    hide_completed: bool,          // toggle to hide completed items
+    tag_filter: Option<String>,    // only show items with this tag
+    show_details: bool,            // detail pane beside the list
+    collapsed: HashSet<u64>,       // parents whose subtasks are hidden
+    message: Option<String>,       // error shown in place of the help bar
+    search: Option<Regex>,         // only show items matching it (and their parents)
+    search_query: String,          // the text the search was built from
+    workflow: Vec<String>,         // statuses `s` cycles through
+    board: bool,                   // one column per status instead of the list
+    repos: Vec<(String, PathBuf)>, // (repo, backlog file) of every repo with --all
+    move_target: usize,            // repo the selected item is being moved to
+}
+
+impl App {
+    fn new(backlog: Backlog, backlog_path: PathBuf, repos: Vec<(String, PathBuf)>) -> Self {
+        let workflow = load_config(&backlog_path).unwrap_or_default().workflow();
+        Self {
+            backlog,
+            backlog_path,
+            selected: 0,
+            scroll_offset: 0,
+            mode: Mode::Normal,
+            edit_buffer: String::new(),
+            edit_cursor: 0,
+            output: None,
+            pending_d: false,
+            hide_completed: false,
+            tag_filter: None,
+            show_details: true,
+            collapsed: HashSet::new(),
+            message: None,
+            search: None,
+            search_query: String::new(),
+            workflow,
+            board: false,
+            repos,
+            move_target: 0,
+        }
+    }
+
+    /// Returns indices of visible items based on hide_completed, tag_filter, the
+    /// search and collapsed parents. While searching, parents of matching items
+    /// stay visible for context and collapsed subtasks are shown.
+    fn visible_indices(&self) -> Vec<usize> {
+        self.backlog
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| !self.hide_completed || !item.is_done())
+            .filter(|(_, item)| self.tag_filter.as_ref().is_none_or(|t| item.has_tag(t)))
+            .filter(|(i, _)| match &self.search {
+                Some(search) => (*i..self.backlog.subtree_end(*i))
+                    .any(|j| self.backlog.items[j].matches(search)),
+                None => !self
+                    .backlog
+                    .ancestors(*i)
+                    .iter()
+                    .any(|id| self.collapsed.contains(id)),
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Converts a visible index to the actual backlog index
+    fn visible_to_actual(&self, visible_idx: usize) -> Option<usize> {
+        self.visible_indices().get(visible_idx).copied()
+    }
+
+    /// Converts an actual backlog index to a visible index
+    fn actual_to_visible(&self, actual_idx: usize) -> Option<usize> {
+        self.visible_indices().iter().position(|&i| i == actual_idx)
+    }
+
+    /// ID of the selected item, which survives reloads and reordering
+    fn selected_id(&self) -> Option<u64> {
+        self.visible_to_actual(self.selected)
+            .map(|actual_idx| self.backlog.items[actual_idx].id)
+    }
+
+    /// Keeps the selection within the visible items
+    fn clamp_selection(&mut self) {
+        let visible = self.visible_indices();
+        if visible.is_empty() {
+            self.selected = 0;
+        } else if self.selected >= visible.len() {
+            self.selected = visible.len() - 1;
+        }
+    }
+
+    /// Selects the item with the given ID, or clamps the selection if it's hidden or gone
+    fn select_id(&mut self, id: u64) {
+        let visible_idx = self
+            .backlog
+            .items
+            .iter()
+            .position(|item| item.id == id)
+            .and_then(|actual_idx| self.actual_to_visible(actual_idx));
+        match visible_idx {
+            Some(visible_idx) => self.selected = visible_idx,
+            None => self.clamp_selection(),
+        }
+    }
+
+    fn toggle_hide_completed(&mut self) {
+        self.hide_completed = !self.hide_completed;
+        // Adjust selection if current selection is no longer visible
+        self.clamp_selection();
+    }
+
+    /// Steps the tag filter through every tag in the backlog, then back to no filter
+    fn cycle_tag_filter(&mut self) {
+        let mut tags: Vec<&String> = self.backlog.items.iter().flat_map(|i| &i.tags).collect();
+        tags.sort();
+        tags.dedup();
+        let next = match &self.tag_filter {
+            None => tags.first(),
+            Some(current) => tags.iter().find(|t| **t > current),
+        };
+        self.tag_filter = next.map(|t| t.to_string());
+        self.clamp_selection();
+    }
+
+    fn enter_search_mode(&mut self) {
+        self.edit_buffer.clear();
+        self.edit_cursor = 0;
+        self.search = None;
+        self.mode = Mode::Search;
+    }
+
+    /// Narrows the list to the query typed so far, keeping the selection if it still matches
+    fn update_search(&mut self) {
+        let id = self.selected_id();
+        self.search_query = self.edit_buffer.clone();
+        self.search = if self.edit_buffer.is_empty() {
+            None
+        } else {
+            search_pattern(&self.edit_buffer, false).ok()
+        };
+        match id {
+            Some(id) => self.select_id(id),
+            None => self.clamp_selection(),
+        }
+    }
+
+    /// Keeps the search applied and jumps to a match if the selection isn't one
+    fn confirm_search(&mut self) {
+        self.mode = Mode::Normal;
+        let on_match = self
+            .visible_to_actual(self.selected)
+            .zip(self.search.as_ref())
+            .is_some_and(|(idx, search)| self.backlog.items[idx].matches(search));
+        if !on_match {
+            self.jump_to_match(true);
+        }
+    }
+
+    fn clear_search(&mut self) {
+        let id = self.selected_id();
+        self.search = None;
+        self.mode = Mode::Normal;
+        if let Some(id) = id {
+            self.select_id(id);
+        }
+    }
+
+    /// Selects the next (or previous) visible item matching the search, wrapping around
+    fn jump_to_match(&mut self, forward: bool) {
+        let Some(search) = &self.search else {
+            return;
+        };
+        let visible = self.visible_indices();
+        let count = visible.len();
+        let target = (1..=count)
+            .map(|step| {
+                if forward {
+                    (self.selected + step) % count
+                } else {
+                    (self.selected + count - step) % count
+                }
+            })
+            .find(|&v| self.backlog.items[visible[v]].matches(search));
+        if let Some(target) = target {
+            self.selected = target;
+        }
+    }
+
+    /// Applies a change under the backlog lock. The file is reloaded first so
+    /// edits made meanwhile by the CLI, scripts or another TUI are not lost.
+    /// On failure nothing is written and the error is shown in the help bar.
+    /// The change is recorded in the undo journal as `action`.
+    fn update(&mut self, action: &str, f: impl FnOnce(&mut Backlog)) -> bool {
+        let path = &self.backlog_path;
+        let result = (|| {
+            let _lock = lock_file(path)?;
+            let mut latest = load_backlog(path)?;
+            let before = latest.clone();
+            f(&mut latest);
+            save_change(path, action, before, &latest)?;
+            Ok::<_, io::Error>(latest)
+        })();
+        match result {
+            Ok(latest) => {
+                self.backlog = latest;
+                true
+            }
+            Err(e) => {
+                self.message = Some(e.to_string());
+                false
+            }
+        }
+    }
+
+    fn move_up(&mut self) {
+        if self.selected > 0 {
+            self.selected -= 1;
+        }
+    }
+
+    fn move_down(&mut self) {
+        let visible_count = self.visible_indices().len();
+        if visible_count > 0 && self.selected < visible_count - 1 {
+            self.selected += 1;
+        }
+    }
+
+    fn toggle_done(&mut self) {
+        if let Some(id) = self.selected_id() {
+            self.update(&format!("toggle @{}", id), |backlog| {
+                if let Some(item) = backlog.items.iter_mut().find(|i| i.id == id) {
+                    item.set_done(!item.is_done());
+                }
+            });
+            // If we just completed an item and hide_completed is on, this clamps the selection
+            self.select_id(id);
+        }
+    }
+
+    /// Moves the selected item on to the next status of the workflow, wrapping
+    /// around from done to todo
+    fn cycle_status(&mut self) {
+        if let Some(id) = self.selected_id() {
+            let workflow = &self.workflow;
+            let next = |status: &str| {
+                let pos = workflow.iter().position(|s| s == status);
+                workflow[pos.map_or(0, |p| (p + 1) % workflow.len())].clone()
+            };
+            let status = match self.backlog.index_of(id) {
+                Some(idx) => next(&self.backlog.items[idx].status),
+                None => return,
+            };
+            self.update(&format!("status @{}", id), |backlog| {
+                if let Some(item) = backlog.items.iter_mut().find(|i| i.id == id) {
+                    item.set_status(&status);
+                }
+            });
+            self.select_id(id);
+        }
+    }
+
+    fn set_notes(&mut self, id: u64, notes: Option<String>) {
+        self.update(&format!("note @{}", id), |backlog| {
+            if let Some(item) = backlog.items.iter_mut().find(|i| i.id == id) {
+                item.notes = notes;
+                item.touch();
+            }
+        });
+        self.select_id(id);
+    }
+
+    fn cycle_priority(&mut self) {
+        if let Some(id) = self.selected_id() {
+            self.update(&format!("priority @{}", id), |backlog| {
+                if let Some(item) = backlog.items.iter_mut().find(|i| i.id == id) {
+                    item.priority = Priority::cycle(item.priority);
+                    item.touch();
+                }
+            });
+            self.select_id(id);
+        }
+    }
+
+    fn move_item_up(&mut self) {
+        self.restructure("move", |backlog, idx| {
+            backlog.move_among_siblings(idx, true)
+        });
+    }
+
+    fn move_item_down(&mut self) {
+        self.restructure("move", |backlog, idx| {
+            backlog.move_among_siblings(idx, false)
+        });
+    }
+
+    fn indent_selected(&mut self) {
+        self.restructure("indent", Backlog::indent);
+    }
+
+    fn outdent_selected(&mut self) {
+        self.restructure("outdent", Backlog::outdent);
+    }
+
+    /// Applies a tree change to the selected item and keeps the selection on it
+    fn restructure(&mut self, name: &str, f: impl FnOnce(&mut Backlog, usize) -> bool) {
+        if let Some(id) = self.selected_id() {
+            let action = format!("{} @{}", name, id);
+            self.update(&action, |backlog| {
+                if let Some(idx) = backlog.index_of(id) {
+                    f(backlog, idx);
+                }
+            });
+            // Update selection to follow the item
+            self.select_id(id);
+        }
+    }
+
+    /// Board column an item belongs to. Statuses that are no longer part of the
+    /// workflow show up in the first column.
+    fn column_of(&self, item: &BacklogItem) -> usize {
+        self.workflow
+            .iter()
+            .position(|s| *s == item.status)
+            .unwrap_or(0)
+    }
+
+    /// Visible indices of the cards in each board column, in backlog order
+    fn board_columns(&self) -> Vec<Vec<usize>> {
+        let mut columns = vec![Vec::new(); self.workflow.len()];
+        for (visible_idx, actual_idx) in self.visible_indices().into_iter().enumerate() {
+            columns[self.column_of(&self.backlog.items[actual_idx])].push(visible_idx);
+        }
+        columns
+    }
+
+    /// Column and row of the focused card
+    fn board_position(&self) -> Option<(usize, usize)> {
+        self.board_columns()
+            .iter()
+            .enumerate()
+            .find_map(|(c, cards)| Some((c, cards.iter().position(|&v| v == self.selected)?)))
+    }
+
+    /// Focuses the card above or below within the same column
+    fn move_in_column(&mut self, down: bool) {
+        let Some((column, row)) = self.board_position() else {
+            return;
+        };
+        let cards = &self.board_columns()[column];
+        let target = if down {
+            cards.get(row + 1)
+        } else {
+            row.checked_sub(1).and_then(|r| cards.get(r))
+        };
+        if let Some(&target) = target {
+            self.selected = target;
+        }
+    }
+
+    /// Focuses the nearest card in the next non-empty column to the left or right
+    fn focus_column(&mut self, right: bool) {
+        let Some((column, row)) = self.board_position() else {
+            return;
+        };
+        let columns = self.board_columns();
+        let target = if right {
+            columns[column + 1..].iter().find(|cards| !cards.is_empty())
+        } else {
+            columns[..column]
+                .iter()
+                .rev()
+                .find(|cards| !cards.is_empty())
+        };
+        if let Some(cards) = target {
+            self.selected = cards[row.min(cards.len() - 1)];
+        }
+    }
+
+    /// Moves the focused card to the previous or next status of the workflow
+    fn move_card(&mut self, right: bool) {
+        let Some(idx) = self.visible_to_actual(self.selected) else {
+            return;
+        };
+        let id = self.backlog.items[idx].id;
+        let column = self.column_of(&self.backlog.items[idx]);
+        let target = if right {
+            column + 1
+        } else {
+            match column.checked_sub(1) {
+                Some(target) => target,
+                None => return,
+            }
+        };
+        let Some(status) = self.workflow.get(target).cloned() else {
+            return;
+        };
+        self.update(&format!("status @{}", id), |backlog| {
+            if let Some(item) = backlog.items.iter_mut().find(|i| i.id == id) {
+                item.set_status(&status);
+            }
+        });
+        self.select_id(id);
+    }
+
+    /// Moves the focused card past the next (or previous) card in its column that
+    /// shares its parent, so subtasks stay with their parent
+    fn reorder_card(&mut self, down: bool) {
+        let Some(id) = self.selected_id() else {
+            return;
+        };
+        let visible = self.visible_indices();
+        let idx = visible[self.selected];
+        let item = &self.backlog.items[idx];
+        let column = self.column_of(item);
+        let mut candidates = visible.iter().filter(|&&i| {
+            let other = &self.backlog.items[i];
+            other.parent == item.parent && self.column_of(other) == column
+        });
+        let target = if down {
+            candidates.find(|&&i| i > idx)
+        } else {
+            candidates.rfind(|&&i| i < idx)
+        };
+        let Some(target) = target.map(|&i| self.backlog.items[i].id) else {
+            return;
+        };
+        self.restructure("move", |backlog, idx| {
+            let mut idx = idx;
+            while let Some(t) = backlog.index_of(target) {
+                let passed = if down { idx > t } else { idx < t };
+                if passed || !backlog.move_among_siblings(idx, !down) {
+                    break;
+                }
+                idx = backlog.index_of(id).unwrap_or(idx);
+            }
+            backlog.items[idx].touch();
+            true
+        });
+    }
+
+    /// Undoes (or redoes) the last change from the journal shared with the CLI
+    fn step_history(&mut self, redo: bool) {
+        let id = self.selected_id();
+        match step_history(&self.backlog_path, redo, false) {
+            Ok((_, backlog)) => {
+                self.backlog = backlog;
+                match id {
+                    Some(id) => self.select_id(id),
+                    None => self.clamp_selection(),
+                }
+            }
+            Err(failure) => self.message = Some(failure.message),
+        }
+    }
+
+    /// Expands or collapses the selected item's subtasks
+    fn toggle_collapse(&mut self) {
+        if let Some(id) = self.selected_id()
+            && self.backlog.items.iter().any(|i| i.parent == Some(id))
+            && !self.collapsed.remove(&id)
+        {
+            self.collapsed.insert(id);
+        }
+    }
+
+    fn enter_edit_mode(&mut self) {
+        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
+            self.edit_buffer = self.backlog.items[actual_idx].description.clone();
+            self.edit_cursor = self.edit_buffer.len();
+            self.mode = Mode::Edit;
+        }
+    }
+
+    fn confirm_edit(&mut self) {
+        if let Some(id) = self.selected_id() {
+            let (description, tags) = extract_tags(&self.edit_buffer);
+            self.update(&format!("edit @{}", id), |backlog| {
+                if let Some(item) = backlog.items.iter_mut().find(|i| i.id == id) {
+                    item.description = description;
+                    item.add_tags(tags);
+                    item.touch();
+                }
+            });
+            self.select_id(id);
+        }
+        self.mode = Mode::Normal;
+    }
+
+    fn cancel_edit(&mut self) {
+        self.mode = Mode::Normal;
+    }
+
+    fn select_item(&mut self) {
+        if let Some(actual_idx) = self.visible_to_actual(self.selected) {
+            self.output = Some(self.backlog.items[actual_idx].description.clone());
+        }
+    }
+
+    fn delete_selected(&mut self) {
+        if let Some(id) = self.selected_id() {
+            self.update(&format!("remove @{}", id), |backlog| {
+                if let Some(idx) = backlog.index_of(id) {
+                    backlog.remove_subtree(idx);
+                }
+            });
+            self.clamp_selection();
+        }
+        self.mode = Mode::Normal;
+    }
+
+    fn enter_add_mode(&mut self) {
+        self.edit_buffer = String::new();
+        self.edit_cursor = 0;
+        self.mode = Mode::Add;
+    }
+
+    fn confirm_add(&mut self) {
+        let (description, tags) = extract_tags(&self.edit_buffer);
+        if !description.is_empty() {
+            let mut new_id = None;
+            self.update(&format!("add {}", description), |backlog| {
+                let idx = backlog.push(description);
+                backlog.items[idx].tags = tags;
+                new_id = Some(backlog.items[idx].id);
+            });
+            // Select the newly added item (it's not done, so always visible)
+            if let Some(id) = new_id {
+                self.select_id(id);
+            }
+        }
+        self.mode = Mode::Normal;
+    }
+
+    fn cancel_add(&mut self) {
+        self.mode = Mode::Normal;
+    }
+
+    /// Position of the open backlog among `repos`
+    fn current_repo(&self) -> usize {
+        self.repos
+            .iter()
+            .position(|(_, path)| *path == self.backlog_path)
+            .unwrap_or(0)
+    }
+
+    /// Opens the previous (or next) repo's backlog, keeping the filters and view
+    fn step_repo(&mut self, forward: bool) {
+        let count = self.repos.len();
+        if count < 2 {
+            return;
+        }
+        let current = self.current_repo();
+        let (_, path) = &self.repos[if forward {
+            (current + 1) % count
+        } else {
+            (current + count - 1) % count
+        }];
+        let path = path.clone();
+        match load_backlog(&path) {
+            Ok(backlog) => {
+                self.workflow = load_config(&path).unwrap_or_default().workflow();
+                self.backlog = backlog;
+                self.backlog_path = path;
+                self.collapsed.clear();
+                self.selected = 0;
+                self.scroll_offset = 0;
+            }
+            Err(e) => self.message = Some(e.to_string()),
+        }
+    }
+
+    fn enter_move_mode(&mut self) {
+        if self.repos.len() > 1 && self.selected_id().is_some() {
+            self.move_target = self.current_repo();
+            self.mode = Mode::MoveToRepo;
+        }
+    }
+
+    /// Steps the repo an item is about to be moved to through the sidebar
+    fn step_move_target(&mut self, down: bool) {
+        if down && self.move_target + 1 < self.repos.len() {
+            self.move_target += 1;
+        } else if !down && self.move_target > 0 {
+            self.move_target -= 1;
+        }
+    }
+
+    /// Moves the selected item and its subtasks to the end of the target repo's
+    /// backlog, where they get fresh IDs. Both backlogs record the move in their
+    /// own undo journal.
+    fn move_to_repo(&mut self) {
+        self.mode = Mode::Normal;
+        let Some(id) = self.selected_id() else {
+            return;
+        };
+        let from = self.backlog_path.clone();
+        let (from_repo, _) = &self.repos[self.current_repo()];
+        let (to_repo, to) = &self.repos[self.move_target];
+        if *to == from {
+            return;
+        }
+        let result = (|| {
+            // Lock in a fixed order so TUIs moving items between the same two
+            // repos in opposite directions can't wait on each other forever
+            let (first, second) = if from < *to { (&from, to) } else { (to, &from) };
+            let _locks = (lock_file(first)?, lock_file(second)?);
+            let mut source = load_backlog(&from)?;
+            let mut target = load_backlog(to)?;
+            let Some(idx) = source.index_of(id) else {
+                return Ok(source);
+            };
+            let (source_before, target_before) = (source.clone(), target.clone());
+            target.adopt(source.remove_subtree(idx));
+            // Write the copy first, so a failure in between can't lose the item
+            let action = format!("move @{} from {}", id, from_repo);
+            save_change(to, &action, target_before, &target)?;
+            let action = format!("move @{} to {}", id, to_repo);
+            save_change(&from, &action, source_before, &source)?;
+            Ok::<_, io::Error>(source)
+        })();
+        match result {
+            Ok(source) => {
+                self.backlog = source;
+                self.clamp_selection();
+            }
+            Err(e) => self.message = Some(e.to_string()),
+        }
+    }
+}
+
+/// One visible row of the list and where its item sits in the subtask tree
+struct ListRow<'a> {
+    /// Index into the backlog's items
+    index: usize,
+    item: &'a BacklogItem,
+    depth: usize,
+    /// `(done, total)` over direct subtasks, if the item has any
+    progress: Option<(usize, usize)>,
+    collapsed: bool,
+    /// Waiting on items that aren't done yet
+    blocked: bool,
+}
+
+/// A custom widget for rendering the backlog list with wrapped items
+struct BacklogList<'a> {
+    /// Visible rows in display order
+    items: Vec<ListRow<'a>>,
+    selected: usize,
+    scroll_offset: usize,
+    title: String,
+    /// When true, use sequential numbering (1, 2, 3...) instead of original indices
+    renumber: bool,
+    /// Search matches to highlight in descriptions
+    highlight: Option<&'a Regex>,
+}
+
+impl<'a> BacklogList<'a> {
+    fn new(
+        items: Vec<ListRow<'a>>,
+        selected: usize,
+        scroll_offset: usize,
+        title: String,
+        renumber: bool,
+        highlight: Option<&'a Regex>,
+    ) -> Self {
+        Self {
+            items,
+            selected,
+            scroll_offset,
+            title,
+            renumber,
+            highlight,
+        }
+    }
+}
+
+impl Widget for BacklogList<'_> {
+    fn render(self, area: Rect, buf: &mut ratatui::buffer::Buffer) {
+        let block = Block::default().borders(Borders::ALL).title(self.title);
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        if inner.width < 10 || inner.height < 1 {
+            return;
+        }
+
+        // Prefixes look like "1. [x] @7 "; pad them all to the widest one so text lines up
+        let prefixes: Vec<String> = self
+            .items
+            .iter()
+            .enumerate()
+            .map(|(visible_idx, row)| {
+                let checkbox = row.item.checkbox();
+                let display_num = if self.renumber {
+                    visible_idx + 1
+                } else {
+                    row.index + 1
+                };
+                format!("{}. {} @{} ", display_num, checkbox, row.item.id)
+            })
+            .collect();
+        let prefix_width = prefixes
+            .iter()
+            .map(|p| p.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(inner.width as usize / 2) as u16;
+        let text_width = inner.width.saturating_sub(prefix_width) as usize;
+
+        let mut y = 0u16;
+        for (visible_idx, row) in self.items.iter().enumerate().skip(self.scroll_offset) {
+            if y >= inner.height {
+                break;
+            }
+            let item = row.item;
+
+            let prefix = &prefixes[visible_idx];
+
+            let style = if visible_idx == self.selected {
+                if item.is_done() {
+                    Style::default()
+                        .fg(Color::DarkGray)
+                        .add_modifier(Modifier::REVERSED)
+                } else if item.is_overdue() {
+                    Style::default()
+                        .fg(Color::Red)
+                        .add_modifier(Modifier::REVERSED)
+                } else {
+                    Style::default().add_modifier(Modifier::REVERSED)
+                }
+            } else if item.is_done() {
+                Style::default().fg(Color::DarkGray)
+            } else if item.is_overdue() {
+                Style::default().fg(Color::Red)
+            } else {
+                Style::default()
+            };
+            // Blocked items are dimmed until everything they wait on is done
+            let style = if row.blocked && !item.is_done() {
+                style.add_modifier(Modifier::DIM)
+            } else {
+                style
+            };
+
+            // Tree indentation and fold marker, then the priority label before the
+            // description and tags after it, each in its own color
+            let mut segments: Vec<(String, Style)> = Vec::new();
+            let fold = match (row.progress, row.collapsed) {
+                (Some(_), true) => "▸ ",
+                (Some(_), false) => "▾ ",
+                (None, _) if row.depth > 0 => "  ",
+                (None, _) => "",
+            };
+            segments.push((format!("{}{}", "  ".repeat(row.depth), fold), style));
+            if let Some(p) = item.priority {
+                let label_style = if item.is_done() {
+                    style
+                } else {
+                    style.fg(p.color())
+                };
+                segments.push((format!("({}) ", p.label()), label_style));
+            }
+            let mut rest = item.description.as_str();
+            if let Some(highlight) = self.highlight {
+                let match_style = style.fg(Color::Black).bg(Color::Yellow);
+                let mut end = 0;
+                for m in highlight.find_iter(&item.description) {
+                    segments.push((item.description[end..m.start()].to_string(), style));
+                    segments.push((m.as_str().to_string(), match_style));
+                    end = m.end();
+                }
+                rest = &item.description[end..];
+            }
+            segments.push((rest.to_string(), style));
+            if let Some((done, total)) = row.progress {
+                segments.push((format!(" [{}/{}]", done, total), style));
+            }
+            for tag in &item.tags {
+                let tag_style = if item.is_done() {
+                    style
+                } else {
+                    style.fg(Color::Cyan)
+                };
+                segments.push((format!(" #{}", tag), tag_style));
+            }
+            if let Some(due) = item.due {
+                let due_style = if item.is_done() || item.is_overdue() {
+                    style
+                } else {
+                    style.fg(Color::DarkGray)
+                };
+                segments.push((format!(" (due {})", due), due_style));
+            }
+            if item.comment_gone() {
+                let gone_style = if item.is_done() {
+                    style
+                } else {
+                    style.fg(Color::DarkGray)
+                };
+                segments.push((" (comment gone)".to_string(), gone_style));
+            }
+            let styled_chars: Vec<(char, Style)> = segments
+                .iter()
+                .flat_map(|(text, seg_style)| text.chars().map(move |c| (c, *seg_style)))
+                .collect();
+
+            // Wrap the description text
+            let lines: Vec<&[(char, Style)]> = if text_width > 0 && !styled_chars.is_empty() {
+                styled_chars.chunks(text_width).collect()
+            } else {
+                vec![&styled_chars[..]]
+            };
+
+            for (line_idx, line_text) in lines.iter().enumerate() {
+                if y >= inner.height {
+                    break;
+                }
+
+                let x_start = inner.x;
+                let y_pos = inner.y + y;
+
+                // Render prefix only on first line
+                if line_idx == 0 {
+                    let mut prefix_chars = prefix.chars();
+                    for j in 0..prefix_width {
+                        let ch = prefix_chars.next().unwrap_or(' ');
+                        buf[(x_start + j, y_pos)].set_char(ch).set_style(style);
+                    }
+                } else {
+                    // Indent continuation lines
+                    for j in 0..prefix_width {
+                        buf[(x_start + j, y_pos)].set_char(' ').set_style(style);
+                    }
+                }
+
+                // Render the text portion
+                for (j, (ch, char_style)) in line_text.iter().enumerate() {
+                    let x_pos = x_start + prefix_width + j as u16;
+                    if x_pos < inner.x + inner.width {
+                        buf[(x_pos, y_pos)].set_char(*ch).set_style(*char_style);
+                    }
+                }
+
+                // Fill remaining width with style (for reversed highlight)
+                let text_end = prefix_width + line_text.len() as u16;
+                for j in text_end..inner.width {
+                    buf[(x_start + j, y_pos)].set_char(' ').set_style(style);
+                }
+
+                y += 1;
+            }
+        }
+    }
+}
+
+/// Runs the TUI on a backlog. With `repos` (for `cli --all`) a sidebar lists
+/// them all and any of them can be opened, even while it's empty.
+fn run_tui(backlog_path: PathBuf, repos: Vec<(String, PathBuf)>) -> io::Result<Option<String>> {
+    let backlog = load_backlog(&backlog_path)?;
+
+    if backlog.items.is_empty() && repos.is_empty() {
+        return Ok(None);
+    }
+
+    enable_raw_mode()?;
+    let mut stdout = stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let mut app = App::new(backlog, backlog_path, repos);
+
+    loop {
+        let has_input_box = matches!(app.mode, Mode::Edit | Mode::Add | Mode::Search);
+
+        // First pass: calculate layout to get actual list height
+        let size = terminal.size()?;
+        let area = Rect::new(0, 0, size.width, size.height);
+        let constraints = if has_input_box {
+            vec![
+                Constraint::Min(3),
+                Constraint::Length(5),
+                Constraint::Length(4),
+            ]
+        } else {
+            vec![Constraint::Min(3), Constraint::Length(4)]
+        };
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(constraints.clone())
+            .split(area);
+
+        // Inner height = chunk height - 2 for borders
+        // For now, use a conservative estimate: assume each item takes ~2 rows on average
+        let list_height = (chunks[0].height.saturating_sub(2) / 2) as usize;
+
+        // Adjust scroll to keep selection visible
+        if app.selected < app.scroll_offset {
+            app.scroll_offset = app.selected;
+        } else if list_height > 0 && app.selected >= app.scroll_offset + list_height {
+            app.scroll_offset = app.selected - list_height + 1;
+        }
+
+        terminal.draw(|f| {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints(constraints.clone())
+                .split(f.area());
+
+            // Build visible rows with original indices
+            let row = |i: usize| {
+                let item = &app.backlog.items[i];
+                ListRow {
+                    index: i,
+                    item,
+                    depth: app.backlog.depth(i),
+                    progress: app.backlog.progress(i),
+                    collapsed: app.collapsed.contains(&item.id),
+                    blocked: app.backlog.is_blocked(i),
+                }
+            };
+            let visible = app.visible_indices();
+
+            let mut title = if app.board { "Board" } else { "Backlog" }.to_string();
+            if !app.repos.is_empty() {
+                title.push_str(&format!(": {}", app.repos[app.current_repo()].0));
+            }
+            if app.hide_completed {
+                title.push_str(" (hiding completed)");
+            }
+            if let Some(tag) = &app.tag_filter {
+                title.push_str(&format!(" (#{})", tag));
+            }
+            if app.search.is_some() {
+                title.push_str(&format!(" (/{})", app.search_query));
+            }
+
+            // With --all, a sidebar of repos to the left of everything else
+            let main_area = if app.repos.is_empty() {
+                chunks[0]
+            } else {
+                let names: Vec<&str> = app
+                    .repos
+                    .iter()
+                    .map(|(repo, _)| repo.rsplit('/').next().unwrap_or(repo))
+                    .collect();
+                let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0) + 4;
+                let panes = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Length(width.min(30) as u16), Constraint::Min(10)])
+                    .split(chunks[0]);
+
+                let current = app.current_repo();
+                let lines: Vec<Line> = names
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| {
+                        let style = if app.mode == Mode::MoveToRepo && i == app.move_target {
+                            Style::default().fg(Color::Yellow).add_modifier(Modifier::REVERSED)
+                        } else if i == current {
+                            Style::default().add_modifier(Modifier::REVERSED)
+                        } else {
+                            Style::default()
+                        };
+                        Line::styled(format!(" {} ", name), style)
+                    })
+                    .collect();
+                let sidebar =
+                    Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Repos"));
+                f.render_widget(sidebar, panes[0]);
+                panes[1]
+            };
+
+            let list_area = if app.show_details {
+                let panes = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+                    .split(main_area);
+
+                let details: Vec<Line> = visible
+                    .get(app.selected)
+                    .map(|&idx| item_details(&app.backlog, idx))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(Line::from)
+                    .collect();
+                let detail_pane = Paragraph::new(details)
+                    .wrap(ratatui::widgets::Wrap { trim: false })
+                    .block(Block::default().borders(Borders::ALL).title("Details"));
+                f.render_widget(detail_pane, panes[1]);
+                panes[0]
+            } else {
+                main_area
+            };
+
+            if app.board {
+                // One column per status; cards are listed flat, in backlog order
+                let board = Block::default().title(title);
+                let columns = app.board_columns();
+                let areas = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints(vec![Constraint::Ratio(1, columns.len() as u32); columns.len()])
+                    .split(board.inner(list_area));
+                f.render_widget(board, list_area);
+                for ((status, cards), area) in app.workflow.iter().zip(&columns).zip(areas.iter()) {
+                    let focused = cards.iter().position(|&v| v == app.selected);
+                    let scroll_offset = focused
+                        .map_or(0, |row| row.saturating_sub(list_height.saturating_sub(1)));
+                    let rows = cards
+                        .iter()
+                        .map(|&v| ListRow {
+                            depth: 0,
+                            ..row(visible[v])
+                        })
+                        .collect();
+                    let column = BacklogList::new(
+                        rows,
+                        focused.unwrap_or(usize::MAX),
+                        scroll_offset,
+                        format!("{} ({})", status, cards.len()),
+                        true,
+                        app.search.as_ref(),
+                    );
+                    f.render_widget(column, *area);
+                }
+            } else {
+                let list = BacklogList::new(
+                    visible.iter().map(|&i| row(i)).collect(),
+                    app.selected,
+                    app.scroll_offset,
+                    title,
+                    app.hide_completed || app.tag_filter.is_some() || app.search.is_some(),
+                    app.search.as_ref(),
+                );
+                f.render_widget(list, list_area);
+            }
+
+            if has_input_box {
+                let before_cursor: String = app.edit_buffer.chars().take(app.edit_cursor).collect();
+                let cursor_char: String = app
+                    .edit_buffer
+                    .chars()
+                    .skip(app.edit_cursor)
+                    .take(1)
+                    .collect();
+                let after_cursor: String =
+                    app.edit_buffer.chars().skip(app.edit_cursor + 1).collect();
+
+                let cursor_display = if cursor_char.is_empty() {
+                    " ".to_string()
+                } else {
+                    cursor_char
+                };
+
+                let input_text = Line::from(vec![
+                    Span::raw(before_cursor),
+                    Span::styled(
+                        cursor_display,
+                        Style::default().bg(Color::White).fg(Color::Black),
+                    ),
+                    Span::raw(after_cursor),
+                ]);
+
+                let title = match app.mode {
+                    Mode::Add => "Add",
+                    Mode::Search => "Search",
+                    _ => "Edit",
+                };
+                let input_box = Paragraph::new(input_text)
+                    .wrap(ratatui::widgets::Wrap { trim: false })
+                    .block(Block::default().borders(Borders::ALL).title(title));
+                f.render_widget(input_box, chunks[1]);
+            }
+
+            let help_chunk = if has_input_box { chunks[2] } else { chunks[1] };
+
+            let help_text = match app.mode {
+                Mode::Edit | Mode::Add => "Enter:confirm  Esc:cancel",
+                Mode::ConfirmDelete => "Delete item? y:yes  n/Esc:cancel",
+                Mode::Search => "Type to search  Enter:keep results  Esc:clear",
+                Mode::MoveToRepo => "Move item to repo: j/k:choose  Enter:move  Esc:cancel",
+                Mode::Normal if app.search.is_some() => {
+                    "n/N:next/prev match  /:new search  Esc:clear search  a:add  j/k:nav  x:toggle  s:status  e:edit  E:notes  p:priority  dd:del  q:quit"
+                }
+                Mode::Normal if app.board => {
+                    "h/l:move card  J/K:reorder  j/k:nav  ←/→:column  b:list  a:add  x:toggle  s:status  e:edit  E:notes  p:priority  dd:del  u/C-r:undo/redo  t:tag filter  /:search  i:details  q:quit"
+                }
+                Mode::Normal => {
+                    "a:add  j/k:nav  x:toggle  s:status  e:edit  E:notes  p:priority  dd:del  u/C-r:undo/redo  K/J:move  Tab/S-Tab:indent  Space:fold  h:hide done  t:tag filter  /:search  i:details  b:board  q:quit"
+                }
+            };
+            let help_text = if app.repos.is_empty() || app.mode != Mode::Normal {
+                help_text.to_string()
+            } else {
+                format!("[/]:repo  m:move to repo  {}", help_text)
+            };
+            let help_style = if app.mode == Mode::ConfirmDelete {
+                Style::default().fg(Color::Red)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            let help = match &app.message {
+                Some(message) => {
+                    Paragraph::new(message.as_str()).style(Style::default().fg(Color::Red))
+                }
+                None => Paragraph::new(help_text).style(help_style),
+            }
+            .wrap(ratatui::widgets::Wrap { trim: true })
+            .block(Block::default().borders(Borders::ALL));
+            f.render_widget(help, help_chunk);
+        })?;
+
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            app.message = None;
+
             match app.mode {
                 Mode::Normal => {
                     match (key.code, key.modifiers) {
+                        (KeyCode::Esc, _) if app.search.is_some() => app.clear_search(),
                         (KeyCode::Char('q'), _) | (KeyCode::Esc, _) => break,
+                        (KeyCode::Char('b'), _) => {
+                            app.board = !app.board;
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('['), _) => {
+                            app.step_repo(false);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char(']'), _) => {
+                            app.step_repo(true);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('m'), _) => {
+                            app.enter_move_mode();
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('J'), _) | (KeyCode::Down, KeyModifiers::SHIFT)
+                            if app.board =>
+                        {
+                            app.reorder_card(true);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('K'), _) | (KeyCode::Up, KeyModifiers::SHIFT)
+                            if app.board =>
+                        {
+                            app.reorder_card(false);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('j'), _) | (KeyCode::Down, _) if app.board => {
+                            app.move_in_column(true);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('k'), _) | (KeyCode::Up, _) if app.board => {
+                            app.move_in_column(false);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('h'), _) if app.board => {
+                            app.move_card(false);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('l'), _) if app.board => {
+                            app.move_card(true);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Left, _) if app.board => {
+                            app.focus_column(false);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Right, _) if app.board => {
+                            app.focus_column(true);
+                            app.pending_d = false;
+                        }
                         (KeyCode::Char('J'), m) if m.contains(KeyModifiers::SHIFT) => {
                             app.move_item_down();
                             app.pending_d = false;
@@ -646,6 +4607,14 @@ fn run_tui(backlog_path: PathBuf) -> io::Result<Option<String>> {
                             app.toggle_done();
                             app.pending_d = false;
                         }
+                        (KeyCode::Char('s'), _) => {
+                            app.cycle_status();
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('p'), _) => {
+                            app.cycle_priority();
+                            app.pending_d = false;
+                        }
                         (KeyCode::Char('e'), _) => {
                             app.enter_edit_mode();
                             app.pending_d = false;
@@ -658,6 +4627,67 @@ fn run_tui(backlog_path: PathBuf) -> io::Result<Option<String>> {
                             app.toggle_hide_completed();
                             app.pending_d = false;
                         }
+                        (KeyCode::Char('t'), _) => {
+                            app.cycle_tag_filter();
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Tab, _) => {
+                            app.indent_selected();
+                            app.pending_d = false;
+                        }
+                        (KeyCode::BackTab, _) => {
+                            app.outdent_selected();
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char(' '), _) => {
+                            app.toggle_collapse();
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('i'), _) => {
+                            app.show_details = !app.show_details;
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('u'), _) => {
+                            app.step_history(false);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('r'), m) if m.contains(KeyModifiers::CONTROL) => {
+                            app.step_history(true);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('/'), _) => {
+                            app.enter_search_mode();
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('n'), _) => {
+                            app.jump_to_match(true);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('N'), _) => {
+                            app.jump_to_match(false);
+                            app.pending_d = false;
+                        }
+                        (KeyCode::Char('E'), _) => {
+                            app.pending_d = false;
+                            if let Some(actual_idx) = app.visible_to_actual(app.selected) {
+                                let item = &app.backlog.items[actual_idx];
+                                let id = item.id;
+                                let notes = item.notes.clone().unwrap_or_default();
+
+                                // Hand the terminal to the editor, then take it back
+                                disable_raw_mode()?;
+                                execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+                                let edited = edit_in_editor(&notes);
+                                enable_raw_mode()?;
+                                execute!(terminal.backend_mut(), EnterAlternateScreen)?;
+                                terminal.clear()?;
+
+                                match edited {
+                                    Ok(edited) => app.set_notes(id, clean_notes(&edited)),
+                                    Err(e) => app.message = Some(e.to_string()),
+                                }
+                            }
+                        }
                         (KeyCode::Char('d'), _) => {
                             if app.pending_d {
                                 // dd - delete immediately
@@ -680,265 +4710,2283 @@ fn run_tui(backlog_path: PathBuf) -> io::Result<Option<String>> {
                             app.pending_d = false;
                         }
                     }
-                }
-                Mode::ConfirmDelete => match key.code {
-                    KeyCode::Char('y') => app.delete_selected(),
-                    KeyCode::Char('n') | KeyCode::Esc => app.mode = Mode::Normal,
-                    _ => {}
-                },
-                Mode::Edit | Mode::Add => match key.code {
-                    KeyCode::Enter => {
-                        if app.mode == Mode::Add {
-                            app.confirm_add();
-                        } else {
-                            app.confirm_edit();
-                        }
+                }
+                Mode::ConfirmDelete => match key.code {
+                    KeyCode::Char('y') => app.delete_selected(),
+                    KeyCode::Char('n') | KeyCode::Esc => app.mode = Mode::Normal,
+                    _ => {}
+                },
+                Mode::MoveToRepo => match key.code {
+                    KeyCode::Char('j') | KeyCode::Down => app.step_move_target(true),
+                    KeyCode::Char('k') | KeyCode::Up => app.step_move_target(false),
+                    KeyCode::Enter => app.move_to_repo(),
+                    KeyCode::Esc => app.mode = Mode::Normal,
+                    _ => {}
+                },
+                Mode::Edit | Mode::Add | Mode::Search => {
+                    match key.code {
+                        KeyCode::Enter => match app.mode {
+                            Mode::Add => app.confirm_add(),
+                            Mode::Search => app.confirm_search(),
+                            _ => app.confirm_edit(),
+                        },
+                        KeyCode::Esc => match app.mode {
+                            Mode::Add => app.cancel_add(),
+                            Mode::Search => app.clear_search(),
+                            _ => app.cancel_edit(),
+                        },
+                        KeyCode::Backspace if app.edit_cursor > 0 => {
+                            let mut chars: Vec<char> = app.edit_buffer.chars().collect();
+                            chars.remove(app.edit_cursor - 1);
+                            app.edit_buffer = chars.into_iter().collect();
+                            app.edit_cursor -= 1;
+                        }
+                        KeyCode::Delete => {
+                            let chars: Vec<char> = app.edit_buffer.chars().collect();
+                            if app.edit_cursor < chars.len() {
+                                let mut chars = chars;
+                                chars.remove(app.edit_cursor);
+                                app.edit_buffer = chars.into_iter().collect();
+                            }
+                        }
+                        KeyCode::Left if app.edit_cursor > 0 => {
+                            app.edit_cursor -= 1;
+                        }
+                        KeyCode::Right => {
+                            let len = app.edit_buffer.chars().count();
+                            if app.edit_cursor < len {
+                                app.edit_cursor += 1;
+                            }
+                        }
+                        KeyCode::Char(c) => {
+                            let mut chars: Vec<char> = app.edit_buffer.chars().collect();
+                            chars.insert(app.edit_cursor, c);
+                            app.edit_buffer = chars.into_iter().collect();
+                            app.edit_cursor += 1;
+                        }
+                        _ => {}
+                    }
+                    // Narrow the list as the query is typed
+                    if app.mode == Mode::Search {
+                        app.update_search();
+                    }
+                }
+            }
+        }
+    }
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+
+    Ok(app.output)
+}
+
+/// Whether `arg` is one of `cmd`'s options, and if so whether its value is the
+/// next argument (as in `-p high`, but not `-phigh` or `--priority=high`)
+fn known_option(cmd: &clap::Command, arg: &str) -> Option<bool> {
+    let takes_value = |a: &clap::Arg| a.get_action().takes_values();
+    if let Some(long) = arg.strip_prefix("--") {
+        let (name, inline) = match long.split_once('=') {
+            Some((name, _)) => (name, true),
+            None => (long, false),
+        };
+        let found = cmd.get_arguments().find(|a| {
+            a.get_long() == Some(name)
+                || a.get_all_aliases().into_iter().flatten().any(|n| n == name)
+        })?;
+        return Some(takes_value(found) && !inline);
+    }
+    let mut chars = arg.strip_prefix('-')?.chars();
+    let short = chars.next()?;
+    let found = cmd.get_arguments().find(|a| a.get_short() == Some(short))?;
+    Some(takes_value(found) && chars.as_str().is_empty())
+}
+
+/// `add` and `edit` take free text, which may contain words such as `-v` or
+/// `--force`. Puts the subcommand's own options first and everything else
+/// after a `--`, so options work before or after the text and any other word
+/// is kept as part of it.
+fn free_text_args(args: Vec<String>) -> Vec<String> {
+    let mut cli = Cli::command();
+    cli.build();
+    let mut i = 1;
+    while i < args.len() && args[i].starts_with('-') && args[i] != "--" {
+        i += if known_option(&cli, &args[i]) == Some(true) {
+            2
+        } else {
+            1
+        };
+    }
+    let Some(cmd) = args
+        .get(i)
+        .filter(|name| ["add", "edit"].contains(&name.as_str()))
+        .and_then(|name| cli.find_subcommand(name))
+    else {
+        return args;
+    };
+
+    let (mut options, mut text) = (args[..=i].to_vec(), Vec::new());
+    let mut rest = args[i + 1..].iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--" {
+            text.extend(rest.cloned());
+            break;
+        }
+        match known_option(cmd, arg) {
+            Some(takes_value) => {
+                options.push(arg.clone());
+                if takes_value {
+                    options.extend(rest.next().cloned());
+                }
+            }
+            None => text.push(arg.clone()),
+        }
+    }
+    options.push("--".to_string());
+    options.extend(text);
+    options
+}
+
+fn main() {
+    let cli = Cli::parse_from(free_text_args(std::env::args().collect()));
+    FORMAT.get_or_init(|| cli.format);
+
+    match cli.command {
+        Some(Commands::Add {
+            priority,
+            due,
+            under,
+            description,
+        }) => {
+            let (desc, tags) = extract_tags(&description.join(" "));
+            if desc.is_empty() {
+                fail(EXIT_INVALID, "Please provide a description");
+            }
+
+            let (backlog, idx) = modify_backlog(|backlog| {
+                let idx = match under {
+                    Some(parent) => {
+                        let parent_idx = backlog
+                            .resolve(parent)
+                            .ok_or_else(|| Failure::no_such_item(parent))?;
+                        backlog.push_under(parent_idx, desc)
+                    }
+                    None => backlog.push(desc),
+                };
+                backlog.items[idx].priority = priority;
+                backlog.items[idx].tags = tags;
+                backlog.items[idx].due = due;
+                Ok(idx)
+            });
+
+            // Register this repo in the global index
+            if let Some(repo_root) = get_repo_root() {
+                register_repo(&repo_root.to_string_lossy());
+            }
+
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("Added: {}", format_item(idx + 1, &backlog.items[idx]))
+            });
+        }
+
+        Some(Commands::List {
+            all,
+            sort,
+            tags,
+            archived,
+        }) => {
+            let tags: Vec<String> = tags.iter().map(|t| normalize_tag(t)).collect();
+            let matches_tags = |item: &BacklogItem| tags.iter().all(|t| item.has_tag(t));
+            // Backlog order shows subtasks as an indented tree; other orders are flat
+            let line = |backlog: &Backlog, position: usize, item: &BacklogItem| {
+                if sort == SortKey::Position {
+                    backlog.format_line(position - 1)
+                } else {
+                    format_item(position, item)
+                }
+            };
+
+            if all {
+                let index = load_global_index_or_exit();
+                let mut backlogs = Vec::new();
+                for repo_path in &index.repos {
+                    let backlog_file = repo_backlog_file(Path::new(repo_path));
+                    match load_backlog(&backlog_file) {
+                        Ok(backlog) => backlogs.push((repo_path, backlog)),
+                        Err(e) => warn_repo(repo_path, e),
+                    }
+                }
+
+                let records: Vec<_> = backlogs
+                    .iter()
+                    .flat_map(|(repo_path, backlog)| {
+                        backlog
+                            .sorted(sort)
+                            .into_iter()
+                            .filter(|(_, item)| matches_tags(item))
+                            .map(|(position, _)| backlog.record(repo_path, position - 1))
+                    })
+                    .collect();
+                emit_list(&records, || {
+                    if index.repos.is_empty() {
+                        outln!("No backlogs found.");
+                        return;
+                    }
+
+                    for (repo_path, backlog) in &backlogs {
+                        if backlog.items.iter().all(|i| i.is_done()) {
+                            continue;
+                        }
+
+                        let items: Vec<_> = backlog
+                            .sorted(sort)
+                            .into_iter()
+                            .filter(|(_, item)| matches_tags(item))
+                            .collect();
+                        if items.is_empty() {
+                            continue;
+                        }
+
+                        outln!("\n{}", repo_path);
+                        outln!("{}", "-".repeat(repo_path.len()));
+                        for (position, item) in items {
+                            outln!("  {}", line(backlog, position, item));
+                        }
+                    }
+                    outln!();
+                });
+            } else {
+                let Some(backlog_path) = get_repo_backlog_path() else {
+                    fail(EXIT_NOT_A_REPO, "Not in a git repository");
+                };
+
+                let (path, name) = if archived {
+                    (archive_path(&backlog_path), "Archive")
+                } else {
+                    (backlog_path, "Backlog")
+                };
+                let backlog = load_backlog_or_exit(&path);
+                let repo = current_repo();
+                let items: Vec<_> = backlog
+                    .sorted(sort)
+                    .into_iter()
+                    .filter(|(_, item)| matches_tags(item))
+                    .collect();
+                let records: Vec<_> = items
+                    .iter()
+                    .map(|(position, _)| backlog.record(&repo, position - 1))
+                    .collect();
+                emit_list(&records, || {
+                    if backlog.items.is_empty() {
+                        outln!("{} is empty.", name);
+                        return;
                     }
-                    KeyCode::Esc => {
-                        if app.mode == Mode::Add {
-                            app.cancel_add();
-                        } else {
-                            app.cancel_edit();
-                        }
+
+                    outln!("\n{}:", name);
+                    outln!("{}", "-".repeat(name.len() + 1));
+                    for (position, item) in &items {
+                        outln!("{}", line(&backlog, *position, item));
                     }
-                    KeyCode::Backspace => {
-                        if app.edit_cursor > 0 {
-                            let mut chars: Vec<char> = app.edit_buffer.chars().collect();
-                            chars.remove(app.edit_cursor - 1);
-                            app.edit_buffer = chars.into_iter().collect();
-                            app.edit_cursor -= 1;
-                        }
+                    outln!();
+                });
+            }
+        }
+
+        Some(Commands::Done { items, pattern }) => {
+            let (backlog, (ids, already_done)) = modify_backlog(|backlog| {
+                let mut ids = backlog.select(&items)?;
+                if let Some(pattern) = &pattern {
+                    let matched: Vec<u64> = backlog
+                        .matching(pattern)
+                        .into_iter()
+                        .filter(|id| {
+                            backlog
+                                .index_of(*id)
+                                .is_some_and(|i| !backlog.items[i].is_done())
+                        })
+                        .collect();
+                    if matched.is_empty() && ids.is_empty() {
+                        return Err(Failure {
+                            code: EXIT_NO_SUCH_ITEM,
+                            message: format!("No pending items match \"{}\"", pattern),
+                        });
                     }
-                    KeyCode::Delete => {
-                        let chars: Vec<char> = app.edit_buffer.chars().collect();
-                        if app.edit_cursor < chars.len() {
-                            let mut chars = chars;
-                            chars.remove(app.edit_cursor);
-                            app.edit_buffer = chars.into_iter().collect();
+                    ids.extend(matched);
+                    ids.sort_by_key(|id| backlog.index_of(*id));
+                    ids.dedup();
+                }
+                let mut already_done = 0;
+                for &id in &ids {
+                    if let Some(idx) = backlog.index_of(id) {
+                        if backlog.items[idx].is_done() {
+                            already_done += 1;
                         }
+                        backlog.items[idx].set_done(true);
                     }
-                    KeyCode::Left => {
-                        if app.edit_cursor > 0 {
-                            app.edit_cursor -= 1;
-                        }
+                }
+                Ok((ids, already_done))
+            });
+
+            let repo = current_repo();
+            let indices: Vec<usize> = ids.iter().filter_map(|id| backlog.index_of(*id)).collect();
+            let records: Vec<_> = indices.iter().map(|&i| backlog.record(&repo, i)).collect();
+            emit_list(&records, || {
+                for &idx in &indices {
+                    outln!("Marked as done: {}", backlog.items[idx].description);
+                }
+                if ids.len() > 1 {
+                    match already_done {
+                        0 => outln!("{} items marked as done", ids.len()),
+                        n => outln!("{} items marked as done ({} already done)", ids.len(), n),
                     }
-                    KeyCode::Right => {
-                        let len = app.edit_buffer.chars().count();
-                        if app.edit_cursor < len {
-                            app.edit_cursor += 1;
-                        }
+                }
+            });
+        }
+
+        Some(Commands::Undone { item }) => {
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                backlog.items[idx].set_done(false);
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("Reopened: {}", backlog.items[idx].description)
+            });
+        }
+
+        Some(Commands::Remove {
+            items,
+            pattern,
+            done,
+        }) => {
+            let (_, (removed, order, kept)) = modify_backlog(|backlog| {
+                let mut ids = backlog.select(&items)?;
+                if let Some(pattern) = &pattern {
+                    let matched = backlog.matching(pattern);
+                    if matched.is_empty() && ids.is_empty() && !done {
+                        return Err(Failure {
+                            code: EXIT_NO_SUCH_ITEM,
+                            message: format!("No items match \"{}\"", pattern),
+                        });
                     }
-                    KeyCode::Char(c) => {
-                        let mut chars: Vec<char> = app.edit_buffer.chars().collect();
-                        chars.insert(app.edit_cursor, c);
-                        app.edit_buffer = chars.into_iter().collect();
-                        app.edit_cursor += 1;
+                    ids.extend(matched);
+                }
+                let mut kept = 0;
+                if done {
+                    let (completed, pending_subtasks) = backlog.completed_subtrees(None);
+                    ids.extend(completed);
+                    kept = pending_subtasks;
+                }
+                ids.sort_by_key(|id| backlog.index_of(*id));
+                ids.dedup();
+                // Remember where the removed items were, for the output records
+                let order: Vec<u64> = backlog.items.iter().map(|i| i.id).collect();
+                Ok((backlog.remove_all(&ids), order, kept))
+            });
+
+            let repo = current_repo();
+            let records: Vec<_> = removed
+                .iter()
+                .flatten()
+                .map(|item| ItemRecord {
+                    repo: &repo,
+                    position: order.iter().position(|id| *id == item.id).unwrap_or(0) + 1,
+                    item,
+                    done: item.is_done(),
+                    blocked: false,
+                    overdue: item.is_overdue(),
+                })
+                .collect();
+            emit_list(&records, || {
+                if removed.is_empty() {
+                    outln!("Nothing to remove.");
+                }
+                for subtree in &removed {
+                    match subtree.len() - 1 {
+                        0 => outln!("Removed: {}", subtree[0].description),
+                        n => outln!("Removed: {} (and {} subtask(s))", subtree[0].description, n),
                     }
-                    _ => {}
+                }
+                if removed.len() > 1 {
+                    outln!("{} items removed", removed.len());
+                }
+                if kept > 0 {
+                    outln!(
+                        "Kept {} completed item(s) that still have pending subtasks",
+                        kept
+                    );
+                }
+            });
+        }
+
+        Some(Commands::Edit { item, text }) => {
+            let (item, text) = if text.is_empty() {
+                // Don't hold the lock while the editor is open; apply the result by ID afterwards
+                let Some(backlog_path) = get_repo_backlog_path() else {
+                    fail(EXIT_NOT_A_REPO, "Not in a git repository");
+                };
+                let backlog = load_backlog_or_exit(&backlog_path);
+                let Some(idx) = backlog.resolve(item) else {
+                    fail(EXIT_NO_SUCH_ITEM, format!("No such item: {}", item));
+                };
+                match edit_in_editor(&backlog.items[idx].description) {
+                    Ok(edited) => (ItemRef::Id(backlog.items[idx].id), edited),
+                    Err(e) => fail(EXIT_FAILURE, e),
+                }
+            } else {
+                (item, text.join(" "))
+            };
+
+            // Descriptions are one line, so whatever the editor left is joined up
+            let (description, tags) = extract_tags(&text);
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                if description.is_empty() {
+                    return Err("Please provide a description".to_string().into());
+                }
+                backlog.items[idx].description = description;
+                backlog.items[idx].add_tags(tags);
+                backlog.items[idx].touch();
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("{}", backlog.format_line(idx))
+            });
+        }
+
+        Some(Commands::Move { item, to }) => {
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                if to == 0 || to > backlog.items.len() {
+                    return Err(format!(
+                        "Position {} is out of range (1-{})",
+                        to,
+                        backlog.items.len()
+                    )
+                    .into());
+                }
+                Ok(backlog.move_to(idx, to - 1))
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("{}", backlog.format_line(idx))
+            });
+        }
+
+        Some(Commands::Tag { item, tags }) => {
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                backlog.items[idx].add_tags(tags.iter().map(|t| normalize_tag(t)));
+                backlog.items[idx].touch();
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("{}", format_item(idx + 1, &backlog.items[idx]))
+            });
+        }
+
+        Some(Commands::Untag { item, tags }) => {
+            let tags: Vec<String> = tags.iter().map(|t| normalize_tag(t)).collect();
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                backlog.items[idx].tags.retain(|t| !tags.contains(t));
+                backlog.items[idx].touch();
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("{}", format_item(idx + 1, &backlog.items[idx]))
+            });
+        }
+
+        Some(Commands::Block { item, on }) => {
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                for blocker in on {
+                    let blocker_idx = backlog
+                        .resolve(blocker)
+                        .ok_or_else(|| Failure::no_such_item(blocker))?;
+                    backlog.add_blocker(idx, blocker_idx)?;
+                }
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("{}", backlog.format_line(idx))
+            });
+        }
+
+        Some(Commands::Unblock { item, on }) => {
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                backlog.items[idx].touch();
+                if on.is_empty() {
+                    backlog.items[idx].blocked_by.clear();
+                }
+                for blocker in on {
+                    let blocker_idx = backlog
+                        .resolve(blocker)
+                        .ok_or_else(|| Failure::no_such_item(blocker))?;
+                    let blocker_id = backlog.items[blocker_idx].id;
+                    backlog.items[idx].blocked_by.retain(|id| *id != blocker_id);
+                }
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("{}", backlog.format_line(idx))
+            });
+        }
+
+        Some(Commands::Show { item }) => {
+            let Some(backlog_path) = get_repo_backlog_path() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+
+            let backlog = load_backlog_or_exit(&backlog_path);
+            let Some(idx) = backlog.resolve(item) else {
+                fail(EXIT_NO_SUCH_ITEM, format!("No such item: {}", item));
+            };
+
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                for line in item_details(&backlog, idx) {
+                    outln!("{}", line);
+                }
+            });
+        }
+
+        Some(Commands::Note { item }) => {
+            let Some(backlog_path) = get_repo_backlog_path() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+
+            // Don't hold the lock while the editor is open; apply the result by ID afterwards
+            let backlog = load_backlog_or_exit(&backlog_path);
+            let Some(idx) = backlog.resolve(item) else {
+                fail(EXIT_NO_SUCH_ITEM, format!("No such item: {}", item));
+            };
+            let id = backlog.items[idx].id;
+            let notes = backlog.items[idx].notes.clone().unwrap_or_default();
+
+            let edited = match edit_in_editor(&notes) {
+                Ok(edited) => clean_notes(&edited),
+                Err(e) => fail(EXIT_FAILURE, e),
+            };
+
+            let (backlog, idx) = modify_backlog(|backlog| {
+                let idx = backlog.index_of(id).ok_or_else(|| Failure {
+                    code: EXIT_NO_SUCH_ITEM,
+                    message: format!("Item @{} was removed while editing", id),
+                })?;
+                backlog.items[idx].notes = edited;
+                backlog.items[idx].touch();
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("Updated notes: {}", backlog.items[idx].description)
+            });
+        }
+
+        Some(Commands::Due {
+            item: Some(item),
+            date,
+        }) => {
+            let due = match date.as_deref() {
+                Some("none") | None => None,
+                Some(date) => match parse_due_arg(date) {
+                    Ok(due) => Some(due),
+                    Err(e) => fail(EXIT_INVALID, e),
                 },
+            };
+
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                backlog.items[idx].due = due;
+                backlog.items[idx].touch();
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("{}", format_item(idx + 1, &backlog.items[idx]))
+            });
+        }
+
+        Some(Commands::Due { item: None, .. }) => {
+            let index = load_global_index_or_exit();
+
+            let mut backlogs = Vec::new();
+            for repo_path in &index.repos {
+                match load_backlog(&repo_backlog_file(Path::new(repo_path))) {
+                    Ok(backlog) => backlogs.push((repo_path, backlog)),
+                    Err(e) => warn_repo(repo_path, e),
+                }
+            }
+
+            // Every pending dated item, soonest first
+            let mut upcoming: Vec<_> = backlogs
+                .iter()
+                .flat_map(|(repo_path, backlog)| {
+                    (0..backlog.items.len())
+                        .filter(|&i| backlog.items[i].due.is_some() && !backlog.items[i].is_done())
+                        .map(|i| backlog.record(repo_path, i))
+                })
+                .collect();
+            upcoming.sort_by_key(|record| record.item.due);
+
+            emit_list(&upcoming, || {
+                if upcoming.is_empty() {
+                    outln!("Nothing is due.");
+                    return;
+                }
+
+                outln!();
+                for record in &upcoming {
+                    let line = format!(
+                        "{}  {}",
+                        record.repo,
+                        format_item(record.position, record.item)
+                    );
+                    if record.overdue {
+                        outln!("{}", red(&line));
+                    } else {
+                        outln!("{}", line);
+                    }
+                }
+                outln!();
+            });
+        }
+
+        Some(Commands::Priority { item, level }) => {
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                backlog.items[idx].priority = level.into();
+                backlog.items[idx].touch();
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("{}", format_item(idx + 1, &backlog.items[idx]))
+            });
+        }
+
+        Some(Commands::Search { query, all, regex }) => {
+            let pattern = match search_pattern(&query, regex) {
+                Ok(pattern) => pattern,
+                Err(e) => fail(EXIT_INVALID, e),
+            };
+
+            let mut backlogs = Vec::new();
+            if all {
+                let index = load_global_index_or_exit();
+                for repo_path in index.repos {
+                    match load_backlog(&repo_backlog_file(Path::new(&repo_path))) {
+                        Ok(backlog) => backlogs.push((repo_path, backlog)),
+                        Err(e) => warn_repo(&repo_path, e),
+                    }
+                }
+            } else {
+                let Some(backlog_path) = get_repo_backlog_path() else {
+                    fail(EXIT_NOT_A_REPO, "Not in a git repository");
+                };
+                backlogs.push((current_repo(), load_backlog_or_exit(&backlog_path)));
             }
+
+            let records: Vec<_> = backlogs
+                .iter()
+                .flat_map(|(repo_path, backlog)| {
+                    (0..backlog.items.len())
+                        .filter(|&i| backlog.items[i].matches(&pattern))
+                        .map(|i| backlog.record(repo_path, i))
+                })
+                .collect();
+            emit_list(&records, || {
+                if records.is_empty() {
+                    outln!("No matches.");
+                    return;
+                }
+
+                let mut repo = None;
+                outln!();
+                for record in &records {
+                    if all && repo != Some(record.repo) {
+                        outln!("{}", record.repo);
+                        outln!("{}", "-".repeat(record.repo.len()));
+                        repo = Some(record.repo);
+                    }
+                    outln!("{}", format_item(record.position, record.item));
+                    // Show the lines of the notes that matched
+                    let notes = record.item.notes.as_deref().unwrap_or_default();
+                    for line in notes.lines().filter(|l| pattern.is_match(l)) {
+                        outln!("      {}", line.trim());
+                    }
+                }
+                outln!();
+            });
         }
-    }
 
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+        Some(Commands::Next) => {
+            let Some(backlog_path) = get_repo_backlog_path() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
 
-    Ok(app.output)
-}
+            let backlog = load_backlog_or_exit(&backlog_path);
+            let config = match load_config(&backlog_path) {
+                Ok(config) => config,
+                Err(e) => fail(EXIT_CORRUPT, e),
+            };
+            let next = backlog
+                .next_item(&config.started_status())
+                .and_then(|item| backlog.index_of(item.id));
 
-fn main() {
-    let cli = Cli::parse();
+            let repo = current_repo();
+            emit_one(next.map(|idx| backlog.record(&repo, idx)), || match next {
+                Some(idx) => outln!("{}", backlog.items[idx].description),
+                None if backlog.items.iter().any(|i| !i.is_done()) => {
+                    eprintln!("Nothing to pick up: the pending items are blocked or waiting.");
+                }
+                None => {
+                    eprintln!("All done! Backlog is clear.");
+                }
+            });
+        }
+
+        Some(Commands::Archive { older_than }) => {
+            let Some(backlog_path) = get_repo_backlog_path() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+            let archive_file = archive_path(&backlog_path);
+
+            let _lock = lock_backlog_or_exit(&backlog_path);
+            let mut backlog = load_backlog_or_exit(&backlog_path);
+            let mut archive = load_backlog_or_exit(&archive_file);
+
+            let backlog_before = backlog.clone();
+            let archive_before = archive.clone();
+            let (ids, kept) = backlog.completed_subtrees(older_than);
+            // Remember where the archived items were, for the output records
+            let order: Vec<u64> = backlog.items.iter().map(|i| i.id).collect();
+            let archived = backlog.remove_all(&ids);
+            if !archived.is_empty() {
+                // Write the archive first so a failure can't lose items
+                archive.items.extend(archived.iter().flatten().cloned());
+                if let Err(e) = save_backlog(&archive_file, &archive) {
+                    fail(EXIT_FAILURE, format!("Failed to save archive: {}", e));
+                }
+                if let Err(e) = save_backlog(&backlog_path, &backlog) {
+                    fail(EXIT_FAILURE, format!("Failed to save backlog: {}", e));
+                }
+                let changes = Changes::between(&backlog_before, &backlog);
+                let archived = Changes::between(&archive_before, &archive);
+                if let Err(e) =
+                    record_change(&backlog_path, &command_line(), changes, Some(archived))
+                {
+                    fail(EXIT_FAILURE, format!("Failed to save history: {}", e));
+                }
+            }
+
+            let repo = current_repo();
+            let records: Vec<_> = archived
+                .iter()
+                .flatten()
+                .map(|item| ItemRecord {
+                    repo: &repo,
+                    position: order.iter().position(|id| *id == item.id).unwrap_or(0) + 1,
+                    item,
+                    done: item.is_done(),
+                    blocked: false,
+                    overdue: false,
+                })
+                .collect();
+            emit_list(&records, || {
+                if archived.is_empty() {
+                    outln!("Nothing to archive.");
+                }
+                for subtree in &archived {
+                    match subtree.len() - 1 {
+                        0 => outln!("Archived: {}", subtree[0].description),
+                        n => outln!(
+                            "Archived: {} (and {} subtask(s))",
+                            subtree[0].description,
+                            n
+                        ),
+                    }
+                }
+                if archived.len() > 1 {
+                    outln!("{} items archived", archived.len());
+                }
+                if kept > 0 {
+                    outln!(
+                        "Kept {} completed item(s) that still have pending subtasks",
+                        kept
+                    );
+                }
+            });
+        }
+
+        Some(Commands::Restore { item }) => {
+            let Some(backlog_path) = get_repo_backlog_path() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+            let archive_file = archive_path(&backlog_path);
+
+            let _lock = lock_backlog_or_exit(&backlog_path);
+            let mut backlog = load_backlog_or_exit(&backlog_path);
+            let mut archive = load_backlog_or_exit(&archive_file);
+            let Some(archive_idx) = archive.resolve(item) else {
+                fail(
+                    EXIT_NO_SUCH_ITEM,
+                    format!("No such archived item: {}", item),
+                );
+            };
+
+            let backlog_before = backlog.clone();
+            let archive_before = archive.clone();
+            let idx = backlog.restore(archive.remove_subtree(archive_idx));
+
+            // Write the backlog first so a failure can't lose items
+            if let Err(e) = save_backlog(&backlog_path, &backlog) {
+                fail(EXIT_FAILURE, format!("Failed to save backlog: {}", e));
+            }
+            if let Err(e) = save_backlog(&archive_file, &archive) {
+                fail(EXIT_FAILURE, format!("Failed to save archive: {}", e));
+            }
+            let changes = Changes::between(&backlog_before, &backlog);
+            let archived = Changes::between(&archive_before, &archive);
+            if let Err(e) = record_change(&backlog_path, &command_line(), changes, Some(archived)) {
+                fail(EXIT_FAILURE, format!("Failed to save history: {}", e));
+            }
+
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("Restored: {}", backlog.format_line(idx))
+            });
+        }
+
+        Some(Commands::Undo { force }) | Some(Commands::Redo { force }) => {
+            let redo = matches!(cli.command, Some(Commands::Redo { .. }));
+            let Some(backlog_path) = get_repo_backlog_path() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+
+            let action = match step_history(&backlog_path, redo, force) {
+                Ok((action, _)) => action,
+                Err(failure) => fail(failure.code, failure.message),
+            };
+            let (verb, key) = if redo {
+                ("Redid", "redone")
+            } else {
+                ("Undid", "undone")
+            };
+            match output_format() {
+                Format::Plain => outln!("{}: {}", verb, action),
+                Format::Json | Format::Ndjson => {
+                    outln!("{}", serde_json::json!({ key: action }))
+                }
+            }
+        }
+
+        Some(Commands::Cli { all }) => {
+            let (backlog_path, repos) = if all {
+                let index = load_global_index_or_exit();
+                let repos: Vec<(String, PathBuf)> = index
+                    .repos
+                    .into_iter()
+                    .map(|repo| {
+                        let backlog_file = repo_backlog_file(Path::new(&repo));
+                        (repo, backlog_file)
+                    })
+                    .collect();
+                // Start in the current repo if it has a backlog
+                let current = get_repo_backlog_path();
+                let Some((_, first)) = repos
+                    .iter()
+                    .find(|(_, path)| Some(path) == current.as_ref())
+                    .or(repos.first())
+                else {
+                    outln!("No backlogs found.");
+                    return;
+                };
+                (first.clone(), repos)
+            } else {
+                let Some(backlog_path) = get_repo_backlog_path() else {
+                    fail(EXIT_NOT_A_REPO, "Not in a git repository");
+                };
+                (backlog_path, Vec::new())
+            };
+
+            match run_tui(backlog_path, repos) {
+                Ok(Some(output)) => outln!("{}", output),
+                Ok(None) => {}
+                Err(e) => {
+                    fail(EXIT_FAILURE, format!("Error: {}", e));
+                }
+            }
+        }
+
+        Some(Commands::Migrate { to, dry_run }) => {
+            let Some(repo_root) = get_repo_root() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+
+            // Look at the files where they are now, so a dry run touches nothing
+            let mut plan = Vec::new();
+            let moves_dir = has_old_backlog_dir(&repo_root);
+            let dir = if moves_dir {
+                plan.push(format!(
+                    "Move {} to {}",
+                    repo_root.join(".todo").display(),
+                    repo_root.join(".backlog").display()
+                ));
+                repo_root.join(".todo")
+            } else {
+                repo_root.join(".backlog")
+            };
+            let current_path = dir.join("backlog.json");
+            let mut config = match load_config(&current_path) {
+                Ok(config) => config,
+                Err(e) => fail(EXIT_CORRUPT, e),
+            };
+            let to = to.unwrap_or(config.storage);
+
+            let plan_for = |path: &Path, steps, current| -> Result<Vec<String>, Failure> {
+                // Item files keep their format version in the order file
+                if uses_item_files(path)? {
+                    let order = order_path(path);
+                    let version = order.exists().then(|| read_order(&order)).transpose()?;
+                    return upgrade_plan(&order, version.map(|o| o.version), steps, current);
+                }
+                upgrade_plan(path, stored_version(path)?, steps, current)
+            };
+            let steps = (|| -> Result<_, Failure> {
+                Ok((
+                    plan_for(&current_path, BACKLOG_UPGRADES, BACKLOG_VERSION)?,
+                    plan_for(
+                        &archive_path(&current_path),
+                        BACKLOG_UPGRADES,
+                        BACKLOG_VERSION,
+                    )?,
+                    plan_for(&get_global_index_path(), INDEX_UPGRADES, INDEX_VERSION)?,
+                ))
+            })();
+            let (backlog_steps, archive_steps, index_steps) = match steps {
+                Ok(steps) => steps,
+                Err(e) => fail(e.code, e.message),
+            };
+            let converts = to != config.storage;
+            plan.extend(backlog_steps.iter().cloned());
+            plan.extend(archive_steps.iter().cloned());
+            plan.extend(index_steps.iter().cloned());
+            if converts {
+                plan.push(match to {
+                    Storage::Items => format!(
+                        "Split {} into one file per item under {}",
+                        current_path.display(),
+                        items_dir(&current_path).display()
+                    ),
+                    Storage::File => format!(
+                        "Join the files under {} into {}",
+                        items_dir(&current_path).display(),
+                        current_path.display()
+                    ),
+                });
+            }
+
+            let report = serde_json::json!({ "steps": plan, "dry_run": dry_run });
+            if plan.is_empty() {
+                emit_report(report, || {
+                    outln!("Nothing to migrate: the backlog is up to date.")
+                });
+                return;
+            }
+            if dry_run {
+                emit_report(report, || {
+                    for line in &plan {
+                        outln!("{}", line);
+                    }
+                    outln!("\nDry run: nothing was changed.");
+                });
+                return;
+            }
 
-    match cli.command {
-        Some(Commands::Add { description }) => {
             let Some(backlog_path) = get_repo_backlog_path() else {
-                eprintln!("Not in a git repository");
-                std::process::exit(1);
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+            let _lock = lock_backlog_or_exit(&backlog_path);
+            let backlog = load_backlog_or_exit(&backlog_path);
+
+            let archive_file = archive_path(&backlog_path);
+            if !archive_steps.is_empty() {
+                let archive = load_backlog_or_exit(&archive_file);
+                if let Err(e) = save_backlog(&archive_file, &archive) {
+                    fail(EXIT_FAILURE, format!("Failed to save archive: {}", e));
+                }
+            }
+
+            // Write the new layout before switching the config to it, and only
+            // then remove the old one, so an interrupted migration loses nothing
+            config.storage = to;
+            let gone = |result: io::Result<()>| match result {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+            let result = match to {
+                _ if !converts => {
+                    if backlog_steps.is_empty() {
+                        Ok(())
+                    } else {
+                        save_backlog(&backlog_path, &backlog)
+                    }
+                }
+                Storage::Items => ignore_local_files(&backlog_path)
+                    .and_then(|_| write_item_files(&backlog_path, &backlog))
+                    .and_then(|_| save_config(&backlog_path, &config))
+                    .and_then(|_| gone(fs::remove_file(&backlog_path))),
+                Storage::File => write_backlog_file(&backlog_path, &backlog)
+                    .and_then(|_| save_config(&backlog_path, &config))
+                    .and_then(|_| gone(fs::remove_dir_all(items_dir(&backlog_path))))
+                    .and_then(|_| gone(fs::remove_file(order_path(&backlog_path))))
+                    .and_then(|_| {
+                        let attributes = backlog_path.with_file_name(".gitattributes");
+                        remove_lines(&attributes, &["order merge=union"])
+                    }),
             };
+            if let Err(e) = result {
+                fail(EXIT_FAILURE, format!("Failed to migrate backlog: {}", e));
+            }
 
-            let desc = description.join(" ");
-            if desc.is_empty() {
-                eprintln!("Please provide a description");
-                std::process::exit(1);
+            if !index_steps.is_empty() {
+                let _index_lock = match lock_file(&get_global_index_path()) {
+                    Ok(lock) => lock,
+                    Err(e) => fail(EXIT_FAILURE, format!("Failed to lock index: {}", e)),
+                };
+                let index = load_global_index_or_exit();
+                if let Err(e) = save_global_index(&index) {
+                    fail(EXIT_FAILURE, format!("Failed to save index: {}", e));
+                }
             }
 
-            let mut backlog = load_backlog(&backlog_path);
-            backlog.items.push(BacklogItem {
-                description: desc.clone(),
-                created_at: Utc::now(),
-                done: false,
+            emit_report(report, || {
+                for line in &plan {
+                    outln!("{}", line);
+                }
+                outln!("\nDone.");
+                if converts || moves_dir {
+                    outln!(
+                        "Commit {} so the rest of the team picks up the new layout.",
+                        backlog_path.parent().unwrap_or(Path::new(".")).display()
+                    );
+                }
             });
+        }
 
-            if let Err(e) = save_backlog(&backlog_path, &backlog) {
-                eprintln!("Failed to save backlog: {}", e);
-                std::process::exit(1);
+        Some(Commands::MergeDriver { base, ours, theirs }) => {
+            let read = |path: &PathBuf| -> io::Result<Backlog> {
+                let content = fs::read_to_string(path)?;
+                // git passes an empty ancestor when the file was added on both sides
+                if content.trim().is_empty() {
+                    return Ok(Backlog::default());
+                }
+                parse_backlog(&content, path)
+            };
+            let merged = (|| -> io::Result<()> {
+                let merged = merge_backlogs(&read(&base)?, &read(&ours)?, &read(&theirs)?);
+                write_backlog_file(&ours, &merged)
+            })();
+            if let Err(e) = merged {
+                // Leave a plain textual merge with conflict markers behind, which
+                // 'backlog doctor' knows how to recover from
+                let _ = std::process::Command::new("git")
+                    .arg("merge-file")
+                    .args([&ours, &base, &theirs])
+                    .status();
+                fail(EXIT_FAILURE, format!("Item-level merge failed: {}", e));
             }
+        }
 
-            // Register this repo in the global index
-            if let Some(repo_root) = get_repo_root() {
-                register_repo(&repo_root.to_string_lossy());
+        Some(Commands::InstallMergeDriver) => {
+            let Some(repo_root) = get_repo_root() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+            let Some(backlog_path) = get_repo_backlog_path() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+
+            let attributes = backlog_path.with_file_name(".gitattributes");
+            let result = ensure_lines(
+                &attributes,
+                &["backlog.json merge=backlog", "archive.json merge=backlog"],
+            )
+            .and_then(|_| {
+                git(
+                    &repo_root,
+                    &["config", "merge.backlog.name", "backlog item-level merge"],
+                )
+            })
+            .and_then(|_| {
+                git(
+                    &repo_root,
+                    &[
+                        "config",
+                        "merge.backlog.driver",
+                        "backlog merge-driver %O %A %B",
+                    ],
+                )
+            });
+            if let Err(e) = result {
+                fail(
+                    EXIT_FAILURE,
+                    format!("Failed to install the merge driver: {}", e),
+                );
             }
 
-            println!("Added: {}", desc);
+            emit_report(
+                serde_json::json!({ "installed": true, "attributes": attributes }),
+                || {
+                    outln!("Installed the backlog merge driver.");
+                    outln!(
+                        "Commit {}; everyone else needs to run 'backlog install-merge-driver' once too.",
+                        attributes.display()
+                    );
+                },
+            );
         }
 
-        Some(Commands::List { all }) => {
-            if all {
-                let index = load_global_index();
-                if index.repos.is_empty() {
-                    println!("No backlogs found.");
-                    return;
-                }
-
-                for repo_path in &index.repos {
-                    let repo = PathBuf::from(repo_path);
-                    let new_dir = repo.join(".backlog");
-                    let old_dir = repo.join(".todo");
-
-                    // Migrate .todo to .backlog if old exists and new doesn't
-                    if old_dir.exists() && !new_dir.exists() {
-                        let _ = fs::rename(&old_dir, &new_dir);
-                    }
+        Some(Commands::Scan { yes }) => {
+            let Some(repo_root) = get_repo_root() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+            let backlog_path = repo_backlog_file(&repo_root);
 
-                    let backlog_file = new_dir.join("backlog.json");
-                    let backlog = load_backlog(&backlog_file);
+            let markers = match scan_markers(&repo_root) {
+                Ok(markers) => markers,
+                Err(e) => fail(EXIT_FAILURE, format!("Failed to list files: {}", e)),
+            };
+            let archive = load_backlog_or_exit(&archive_path(&backlog_path));
+            let mut preview = load_backlog_or_exit(&backlog_path);
+            let result = reconcile_markers(&mut preview, &archive, &markers);
 
-                    let pending: Vec<_> = backlog.items.iter().filter(|i| !i.done).collect();
-                    if pending.is_empty() {
-                        continue;
+            let plain = output_format() == Format::Plain;
+            if plain {
+                outln!(
+                    "Found {} marker comment(s), {} already in the backlog.",
+                    markers.len(),
+                    markers.len() - result.new.len()
+                );
+                if !result.new.is_empty() {
+                    outln!("\nNew:");
+                    for &i in &result.new {
+                        let marker = &markers[i];
+                        outln!(
+                            "  {}:{}  {} {}",
+                            marker.file,
+                            marker.line,
+                            marker.kind.to_uppercase(),
+                            marker.text
+                        );
                     }
-
-                    println!("\n{}", repo_path);
-                    println!("{}", "-".repeat(repo_path.len()));
-                    for (i, item) in backlog.items.iter().enumerate() {
-                        let status = if item.done { "[x]" } else { "[ ]" };
-                        println!("  {}. {} {}", i + 1, status, item.description);
+                }
+                if !result.gone.is_empty() {
+                    outln!("\nNo longer in the code:");
+                    for idx in result.gone.iter().filter_map(|&id| preview.index_of(id)) {
+                        outln!("  {}", preview.format_line(idx));
                     }
                 }
-                println!();
-            } else {
-                let Some(backlog_path) = get_repo_backlog_path() else {
-                    eprintln!("Not in a git repository");
-                    std::process::exit(1);
-                };
+            }
+            if result.new.is_empty() && result.gone.is_empty() {
+                emit_list::<ScanRecord>(&[], || outln!("Nothing to import."));
+                return;
+            }
 
-                let backlog = load_backlog(&backlog_path);
-                if backlog.items.is_empty() {
-                    println!("Backlog is empty.");
-                    return;
+            let apply = yes
+                || (plain && io::stdin().is_terminal() && {
+                    outln!();
+                    confirm(&format!(
+                        "Import {} item(s) and flag {} whose comment is gone?",
+                        result.new.len(),
+                        result.gone.len()
+                    ))
+                });
+            if !apply {
+                let repo = current_repo();
+                let imports = result.new.iter().map(|&i| ScanRecord {
+                    action: "import",
+                    pending: true,
+                    marker: Some(&markers[i]),
+                    item: None,
+                });
+                let flags = result.gone.iter().filter_map(|&id| preview.index_of(id));
+                let flags = flags.map(|idx| ScanRecord {
+                    action: "flag",
+                    pending: true,
+                    marker: None,
+                    item: Some(preview.record(&repo, idx)),
+                });
+                let records: Vec<_> = imports.chain(flags).collect();
+                emit_list(&records, || {
+                    outln!("\nRun 'backlog scan --yes' to import them.")
+                });
+                return;
+            }
+
+            let (backlog, (imported, gone)) = modify_backlog(|backlog| {
+                let result = reconcile_markers(backlog, &archive, &markers);
+                let mut imported = Vec::new();
+                for i in result.new {
+                    let marker = &markers[i];
+                    let description = if marker.text.is_empty() {
+                        format!(
+                            "{} in {}:{}",
+                            marker.kind.to_uppercase(),
+                            marker.file,
+                            marker.line
+                        )
+                    } else {
+                        marker.text.clone()
+                    };
+                    let idx = backlog.push(description);
+                    let item = &mut backlog.items[idx];
+                    item.add_tags([marker.kind.clone()]);
+                    item.source = Some(Source {
+                        file: marker.file.clone(),
+                        line: marker.line,
+                        text: marker.text.clone(),
+                        missing: false,
+                    });
+                    imported.push(item.id);
                 }
+                Ok((imported, result.gone))
+            });
+            register_repo(&repo_root.to_string_lossy());
 
-                println!("\nBacklog:");
-                println!("--------");
-                for (i, item) in backlog.items.iter().enumerate() {
-                    let status = if item.done { "[x]" } else { "[ ]" };
-                    println!("{}. {} {}", i + 1, status, item.description);
+            let repo = current_repo();
+            let record = |action, id| {
+                let idx = backlog.index_of(id)?;
+                Some(ScanRecord {
+                    action,
+                    pending: false,
+                    marker: None,
+                    item: Some(backlog.record(&repo, idx)),
+                })
+            };
+            let records: Vec<_> = imported
+                .iter()
+                .filter_map(|&id| record("import", id))
+                .chain(gone.iter().filter_map(|&id| record("flag", id)))
+                .collect();
+            emit_list(&records, || {
+                outln!();
+                for idx in imported.iter().filter_map(|&id| backlog.index_of(id)) {
+                    outln!("Imported: {}", backlog.format_line(idx));
                 }
-                println!();
-            }
+                for idx in gone.iter().filter_map(|&id| backlog.index_of(id)) {
+                    outln!("Flagged: {}", backlog.format_line(idx));
+                }
+            });
         }
 
-        Some(Commands::Done { number }) => {
+        Some(Commands::Status { item, status }) => {
+            let status = status.trim().to_lowercase();
             let Some(backlog_path) = get_repo_backlog_path() else {
-                eprintln!("Not in a git repository");
-                std::process::exit(1);
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
             };
+            let workflow = match load_config(&backlog_path) {
+                Ok(config) => config.workflow(),
+                Err(e) => fail(EXIT_CORRUPT, e),
+            };
+            if !workflow.contains(&status) {
+                fail(
+                    EXIT_INVALID,
+                    format!(
+                        "Unknown status '{}'. This repo's statuses are: {}",
+                        status,
+                        workflow.join(", ")
+                    ),
+                );
+            }
+
+            let (backlog, idx) = modify_item(item, |backlog, idx| {
+                backlog.items[idx].set_status(&status);
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                let item = &backlog.items[idx];
+                outln!("Status set to {}: {}", item.status, item.description)
+            });
+        }
 
-            let mut backlog = load_backlog(&backlog_path);
-            if number == 0 || number > backlog.items.len() {
-                eprintln!("Invalid item number");
-                std::process::exit(1);
+        Some(Commands::Start { item }) => {
+            let Some(repo_root) = get_repo_root() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+            let backlog_path = repo_backlog_file(&repo_root);
+            let backlog = load_backlog_or_exit(&backlog_path);
+            let Some(idx) = backlog.resolve(item) else {
+                let failure = Failure::no_such_item(item);
+                fail(failure.code, failure.message);
+            };
+            let target = &backlog.items[idx];
+            if target.is_done() {
+                fail(
+                    EXIT_INVALID,
+                    format!("Item is already done: {}", target.description),
+                );
             }
+            let config = match load_config(&backlog_path) {
+                Ok(config) => config,
+                Err(e) => fail(EXIT_CORRUPT, e),
+            };
 
-            backlog.items[number - 1].done = true;
-            if let Err(e) = save_backlog(&backlog_path, &backlog) {
-                eprintln!("Failed to save backlog: {}", e);
-                std::process::exit(1);
+            // Starting an item again goes back to its branch
+            let branch = target
+                .branch
+                .clone()
+                .unwrap_or_else(|| branch_name(config.branch_prefix(), target));
+            let exists = git(
+                &repo_root,
+                &[
+                    "rev-parse",
+                    "--verify",
+                    "--quiet",
+                    &format!("refs/heads/{}", branch),
+                ],
+            )
+            .is_ok();
+            let checkout = if exists {
+                git(&repo_root, &["checkout", &branch])
+            } else {
+                git(&repo_root, &["checkout", "-b", &branch])
+            };
+            if let Err(e) = checkout {
+                fail(
+                    EXIT_FAILURE,
+                    format!("Failed to check out {}: {}", branch, e),
+                );
             }
 
-            println!("Marked as done: {}", backlog.items[number - 1].description);
+            let started = config.started_status();
+            let (backlog, idx) = modify_item(ItemRef::Id(target.id), |backlog, idx| {
+                let item = &mut backlog.items[idx];
+                if !item.in_progress() {
+                    item.set_status(&started);
+                }
+                item.branch = Some(branch.clone());
+                item.touch();
+                Ok(idx)
+            });
+            let repo = current_repo();
+            emit_one(Some(backlog.record(&repo, idx)), || {
+                outln!("Started: {}", backlog.format_line(idx));
+                if exists {
+                    outln!("Switched to branch '{}'", branch);
+                } else {
+                    outln!("Switched to a new branch '{}'", branch);
+                }
+            });
         }
 
-        Some(Commands::Remove { number }) => {
-            let Some(backlog_path) = get_repo_backlog_path() else {
-                eprintln!("Not in a git repository");
-                std::process::exit(1);
+        Some(Commands::Hook {
+            action: HookAction::Install,
+        }) => {
+            let Some(repo_root) = get_repo_root() else {
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
+            };
+            // Asking git finds the right place in worktrees and with core.hooksPath
+            let hooks_dir = match git(&repo_root, &["rev-parse", "--git-path", "hooks"]) {
+                Ok(dir) => repo_root.join(dir),
+                Err(e) => fail(
+                    EXIT_FAILURE,
+                    format!("Failed to find the hooks directory: {}", e),
+                ),
             };
 
-            let mut backlog = load_backlog(&backlog_path);
-            if number == 0 || number > backlog.items.len() {
-                eprintln!("Invalid item number");
-                std::process::exit(1);
-            }
+            // What happened to each hook: "installed", "already installed" or "skipped"
+            let mut results = Vec::new();
+            for hook in [HookName::CommitMsg, HookName::PostCommit] {
+                let path = hooks_dir.join(hook.file_name());
+                match fs::read_to_string(&path) {
+                    Ok(existing) if existing.contains("backlog hook run") => {
+                        results.push((hook, path, "already installed"));
+                        continue;
+                    }
+                    Ok(_) => {
+                        results.push((hook, path, "skipped"));
+                        continue;
+                    }
+                    Err(_) => {}
+                }
 
-            let removed = backlog.items.remove(number - 1);
-            if let Err(e) = save_backlog(&backlog_path, &backlog) {
-                eprintln!("Failed to save backlog: {}", e);
-                std::process::exit(1);
+                let result = fs::create_dir_all(&hooks_dir)
+                    .and_then(|_| fs::write(&path, hook_script(hook)));
+                #[cfg(unix)]
+                let result = result.and_then(|_| {
+                    use std::os::unix::fs::PermissionsExt;
+                    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+                });
+                if let Err(e) = result {
+                    fail(
+                        EXIT_FAILURE,
+                        format!("Failed to write {}: {}", path.display(), e),
+                    );
+                }
+                results.push((hook, path, "installed"));
             }
 
-            println!("Removed: {}", removed.description);
+            let hooks: Vec<Value> = results
+                .iter()
+                .map(|(hook, path, result)| {
+                    serde_json::json!({ "hook": hook.file_name(), "path": path, "result": result })
+                })
+                .collect();
+            emit_report(serde_json::json!({ "hooks": hooks }), || {
+                for (hook, path, result) in &results {
+                    match *result {
+                        "installed" => outln!("Installed {}", path.display()),
+                        "already installed" => outln!("Already installed: {}", path.display()),
+                        _ => outln!(
+                            "Skipped {}, which already exists. Add this line to it:\n  backlog hook run {} \"$@\"",
+                            path.display(),
+                            hook.file_name()
+                        ),
+                    }
+                }
+            });
         }
 
-        Some(Commands::Next) => {
-            let Some(backlog_path) = get_repo_backlog_path() else {
-                eprintln!("Not in a git repository");
-                std::process::exit(1);
-            };
+        Some(Commands::Hook {
+            action: HookAction::Run { hook, args },
+        }) => match hook {
+            HookName::CommitMsg => {
+                let Some(message_file) = args.first() else {
+                    fail(EXIT_INVALID, "The commit-msg hook needs the message file");
+                };
+                let message = match fs::read_to_string(message_file) {
+                    Ok(message) => message,
+                    Err(e) => fail(
+                        EXIT_FAILURE,
+                        format!("Failed to read {}: {}", message_file, e),
+                    ),
+                };
+                // git drops comment lines from the message, so ignore them too
+                let message: Vec<&str> = message.lines().filter(|l| !l.starts_with('#')).collect();
+                let ids = closing_refs(&message.join("\n"));
+                if ids.is_empty() {
+                    return;
+                }
 
-            let backlog = load_backlog(&backlog_path);
-            let next = backlog.items.iter().find(|i| !i.done);
+                let Some(backlog_path) = get_repo_backlog_path() else {
+                    fail(EXIT_NOT_A_REPO, "Not in a git repository");
+                };
+                let backlog = load_backlog_or_exit(&backlog_path);
+                let archive = load_backlog_or_exit(&archive_path(&backlog_path));
+                let unknown: Vec<String> = ids
+                    .iter()
+                    .filter(|&&id| backlog.index_of(id).is_none() && archive.index_of(id).is_none())
+                    .map(|id| format!("backlog#{}", id))
+                    .collect();
+                if !unknown.is_empty() {
+                    fail(
+                        EXIT_NO_SUCH_ITEM,
+                        format!(
+                            "The commit message refers to {}, which doesn't exist. Items are referenced by their @id.",
+                            unknown.join(", ")
+                        ),
+                    );
+                }
+            }
+            HookName::PostCommit => {
+                let Some(repo_root) = get_repo_root() else {
+                    fail(EXIT_NOT_A_REPO, "Not in a git repository");
+                };
+                let log = match git(&repo_root, &["log", "-1", "--format=%H%n%B"]) {
+                    Ok(log) => log,
+                    Err(e) => fail(EXIT_FAILURE, format!("Failed to read the commit: {}", e)),
+                };
+                let (sha, message) = log.split_once('\n').unwrap_or((&log, ""));
+                let ids = closing_refs(message);
+                let Some(backlog_path) = get_repo_backlog_path() else {
+                    fail(EXIT_NOT_A_REPO, "Not in a git repository");
+                };
+                // Leave the backlog alone unless the commit closes something in it
+                let backlog = load_backlog_or_exit(&backlog_path);
+                if !ids.iter().any(|&id| backlog.index_of(id).is_some()) {
+                    return;
+                }
 
-            match next {
-                Some(item) => println!("{}", item.description),
-                None => {
-                    eprintln!("All done! Backlog is clear.");
+                // An amended commit gets a new SHA, so the latest one always wins
+                let (backlog, closed) = modify_backlog(|backlog| {
+                    let mut closed = Vec::new();
+                    for &id in &ids {
+                        if let Some(idx) = backlog.index_of(id) {
+                            backlog.items[idx].set_done(true);
+                            backlog.items[idx].closed_by = Some(sha.to_string());
+                            closed.push(idx);
+                        }
+                    }
+                    Ok(closed)
+                });
+                for idx in closed {
+                    outln!(
+                        "Closed by {}: {}",
+                        &sha[..sha.len().min(7)],
+                        backlog.items[idx].description
+                    );
                 }
             }
-        }
+        },
 
-        Some(Commands::Cli) => {
+        Some(Commands::Doctor { fix }) => {
             let Some(backlog_path) = get_repo_backlog_path() else {
-                eprintln!("Not in a git repository");
-                std::process::exit(1);
+                fail(EXIT_NOT_A_REPO, "Not in a git repository");
             };
 
-            match run_tui(backlog_path) {
-                Ok(Some(output)) => println!("{}", output),
-                Ok(None) => {}
-                Err(e) => {
-                    eprintln!("Error: {}", e);
-                    std::process::exit(1);
-                }
+            let _lock = lock_backlog_or_exit(&backlog_path);
+            let mut checks = Vec::new();
+            if uses_item_files(&backlog_path).unwrap_or(false) {
+                // Each item file stands on its own, so there's nothing to salvage
+                // beyond pointing at the broken one
+                let dir = items_dir(&backlog_path);
+                checks.push(match load_backlog(&backlog_path) {
+                    Ok(backlog) => FileCheck::healthy(&dir, backlog.items.len()),
+                    Err(e) => FileCheck {
+                        error: Some(e.to_string()),
+                        ..FileCheck::healthy(&dir, 0)
+                    },
+                });
+            } else if backlog_path.exists() {
+                checks.push(doctor_file(&backlog_path, fix));
             }
+            // Archived items are always kept in a single file
+            let archive_file = archive_path(&backlog_path);
+            if archive_file.exists() {
+                checks.push(doctor_file(&archive_file, fix));
+            }
+
+            emit_report(serde_json::json!({ "files": checks }), || {
+                if checks.is_empty() {
+                    outln!("No backlog file at {}", backlog_path.display());
+                }
+                for (i, check) in checks.iter().enumerate() {
+                    if i > 0 {
+                        outln!();
+                    }
+                    check.print();
+                }
+            });
         }
 
         None => {
             // Default: show backlog for current repo
             let Some(backlog_path) = get_repo_backlog_path() else {
-                eprintln!("Not in a git repository. Use 'backlog --help' for usage.");
-                std::process::exit(1);
+                fail(
+                    EXIT_NOT_A_REPO,
+                    "Not in a git repository. Use 'backlog --help' for usage.",
+                );
             };
 
-            let backlog = load_backlog(&backlog_path);
-            if backlog.items.is_empty() {
-                println!("Backlog is empty. Use 'backlog add <description>' to add items.");
-                return;
-            }
-
-            let pending: Vec<_> = backlog.items.iter().filter(|i| !i.done).collect();
-            if pending.is_empty() {
-                println!("All done! Backlog is clear.");
-            } else {
-                println!("\n{} item(s) in backlog:", pending.len());
-                for (i, item) in backlog.items.iter().enumerate() {
-                    if !item.done {
-                        let status = "[ ]";
-                        println!("{}. {} {}", i + 1, status, item.description);
+            let backlog = load_backlog_or_exit(&backlog_path);
+            // The item `backlog start` made the checked-out branch for
+            let branch = get_repo_root()
+                .and_then(|root| git(&root, &["symbolic-ref", "--short", "-q", "HEAD"]).ok());
+            let current = |item: &BacklogItem| branch.is_some() && item.branch == branch;
+            let repo = current_repo();
+            let records: Vec<_> = (0..backlog.items.len())
+                .filter(|&i| !backlog.items[i].is_done())
+                .map(|i| backlog.record(&repo, i))
+                .collect();
+            emit_list(&records, || {
+                if backlog.items.is_empty() {
+                    outln!("Backlog is empty. Use 'backlog add <description>' to add items.");
+                } else if records.is_empty() {
+                    outln!("All done! Backlog is clear.");
+                } else {
+                    outln!("\n{} item(s) in backlog:", records.len());
+                    for (i, item) in backlog.items.iter().enumerate() {
+                        if current(item) && !item.is_done() {
+                            let line = format!("{}  <- current branch", backlog.format_line(i));
+                            outln!("{}", green(&line));
+                        } else if item.is_overdue() {
+                            outln!("{}", red(&backlog.format_line(i)));
+                        } else if !item.is_done() {
+                            outln!("{}", backlog.format_line(i));
+                        }
                     }
+                    outln!();
                 }
-                println!();
-            }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn backlog(descriptions: &[&str]) -> Backlog {
+        let mut backlog = Backlog::default();
+        for description in descriptions {
+            backlog.push(description.to_string());
+        }
+        backlog
+    }
+
+    fn descriptions(backlog: &Backlog) -> Vec<&str> {
+        backlog
+            .items
+            .iter()
+            .map(|item| item.description.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn split_conflict_sides_keeps_shared_lines_and_skips_the_base() {
+        let content =
+            "start\n<<<<<<< HEAD\nours\n||||||| base\nold\n=======\ntheirs\n>>>>>>> branch\nend\n";
+        let (ours, theirs) = split_conflict_sides(content).unwrap();
+        assert_eq!(ours, "start\nours\nend\n");
+        assert_eq!(theirs, "start\ntheirs\nend\n");
+        assert!(split_conflict_sides("no markers\n").is_none());
+    }
+
+    #[test]
+    fn salvage_keeps_both_sides_of_a_conflict() {
+        let ours = backlog(&["a", "ours"]);
+        let mut theirs = backlog(&["a", "theirs"]);
+        theirs.items[0] = ours.items[0].clone();
+        let conflicted = format!(
+            "<<<<<<< HEAD\n{}\n=======\n{}\n>>>>>>> branch\n",
+            serde_json::to_string_pretty(&ours).unwrap(),
+            serde_json::to_string_pretty(&theirs).unwrap()
+        );
+
+        let salvaged = salvage_backlog(&conflicted);
+        assert_eq!(descriptions(&salvaged), ["a", "ours", "theirs"]);
+        let ids: HashSet<u64> = salvaged.items.iter().map(|item| item.id).collect();
+        assert_eq!(ids.len(), 3);
+    }
+
+    #[test]
+    fn salvage_recovers_items_from_truncated_json() {
+        let content = serde_json::to_string_pretty(&backlog(&["a", "b"])).unwrap();
+        let cut = content.find("\"b\"").unwrap();
+        assert_eq!(descriptions(&salvage_backlog(&content[..cut])), ["a"]);
+    }
+
+    #[test]
+    fn upgrades_bring_a_version_0_file_up_to_date() {
+        let content = r#"{
+            "items": [
+                {"description": "a", "created_at": "2024-01-01T00:00:00Z", "done": true},
+                {"description": "b", "created_at": "2024-01-02T00:00:00Z", "done": false,
+                 "id": 7},
+                {"description": "c", "created_at": "2024-01-04T00:00:00Z", "done": false,
+                 "started_at": "2024-01-05T00:00:00Z"}
+            ]
+        }"#;
+        let backlog = parse_backlog(content, Path::new("backlog.json")).unwrap();
+        assert_eq!(backlog.version, BACKLOG_VERSION);
+        let ids: Vec<u64> = backlog.items.iter().map(|item| item.id).collect();
+        assert_eq!(ids, [8, 7, 9]);
+        assert_eq!(backlog.next_id, 10);
+        let statuses: Vec<&str> = backlog.items.iter().map(|i| i.status.as_str()).collect();
+        assert_eq!(statuses, [DONE, TODO, DOING]);
+    }
+
+    #[test]
+    fn upgrade_steps_end_at_the_current_version() {
+        let targets: Vec<u32> = BACKLOG_UPGRADES.iter().map(|step| step.to).collect();
+        assert_eq!(targets, (1..=BACKLOG_VERSION).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn files_from_a_newer_version_are_refused() {
+        let content = format!(r#"{{"version": {}, "items": []}}"#, BACKLOG_VERSION + 1);
+        let err = parse_backlog(&content, Path::new("backlog.json"))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn closing_refs_finds_every_closed_item_once() {
+        assert_eq!(
+            closing_refs("Fixes backlog#12, backlog#14 and backlog#3"),
+            [12, 14, 3]
+        );
+        assert_eq!(closing_refs("closes: backlog#5\n\nResolved backlog#5"), [5]);
+        assert!(closing_refs("See backlog#9").is_empty());
+        assert!(closing_refs("Fixes #9").is_empty());
+    }
+
+    #[test]
+    fn merge_keeps_items_both_sides_added_under_the_same_id() {
+        let base = backlog(&["a"]);
+        let mut ours = base.clone();
+        ours.push("ours".to_string());
+        let mut theirs = base.clone();
+        let idx = theirs.push("theirs".to_string());
+        theirs.items[idx].created_at += chrono::Duration::seconds(1);
+
+        let merged = merge_backlogs(&base, &ours, &theirs);
+        let id_of = |description: &str| {
+            let item = merged.items.iter().find(|i| i.description == description);
+            item.unwrap().id
+        };
+        assert_eq!(merged.items.len(), 3);
+        // Ours keeps the ID, theirs gets a fresh one
+        assert_eq!(id_of("ours"), 2);
+        assert_eq!(id_of("theirs"), 3);
+        assert!(merged.next_id > merged.items.iter().map(|i| i.id).max().unwrap());
+    }
+
+    #[test]
+    fn merge_keeps_an_item_deleted_on_one_side_deleted() {
+        let base = backlog(&["a", "b"]);
+        let mut ours = base.clone();
+        ours.items[1].description = "b, edited".to_string();
+        ours.items[1].touch();
+        let mut theirs = base.clone();
+        theirs.remove_subtree(1);
+
+        assert_eq!(descriptions(&merge_backlogs(&base, &ours, &theirs)), ["a"]);
+        assert_eq!(descriptions(&merge_backlogs(&base, &theirs, &ours)), ["a"]);
+    }
+
+    #[test]
+    fn merge_slots_added_items_into_the_other_sides_order() {
+        let base = backlog(&["a", "b", "c"]);
+        let mut ours = base.clone();
+        ours.items.rotate_right(1);
+        let mut theirs = base.clone();
+        theirs.push("d".to_string());
+
+        assert_eq!(
+            descriptions(&merge_backlogs(&base, &ours, &theirs)),
+            ["c", "d", "a", "b"]
+        );
+        assert_eq!(
+            descriptions(&merge_backlogs(&base, &theirs, &ours)),
+            ["c", "d", "a", "b"]
+        );
+    }
+
+    #[test]
+    fn next_prefers_started_items_and_skips_other_statuses() {
+        let config = RepoConfig {
+            statuses: ["todo", "doing", "review", "blocked", "done"]
+                .map(String::from)
+                .to_vec(),
+            ..RepoConfig::default()
+        };
+        let started = config.started_status();
+        assert_eq!(started, DOING);
+
+        let mut backlog = backlog(&["fix login", "waiting on vendor", "ship it", "polish"]);
+        backlog.items[1].set_status("blocked");
+        backlog.items[2].set_status("review");
+        backlog.items[3].priority = Some(Priority::High);
+        let next = |backlog: &Backlog| backlog.next_item(&started).map(|i| i.id);
+        assert_eq!(next(&backlog), Some(4));
+
+        backlog.items[0].set_status(DOING);
+        assert_eq!(next(&backlog), Some(1));
+
+        backlog.items[0].set_status(DONE);
+        backlog.items[3].set_status(DONE);
+        assert_eq!(next(&backlog), None);
+    }
+
+    #[test]
+    fn started_status_follows_the_workflow() {
+        assert_eq!(RepoConfig::default().started_status(), DOING);
+        let config = RepoConfig {
+            statuses: vec!["todo".to_string(), "active".to_string()],
+            ..RepoConfig::default()
+        };
+        assert_eq!(config.started_status(), "active");
+        let config = RepoConfig {
+            statuses: vec!["todo".to_string(), "done".to_string()],
+            ..RepoConfig::default()
+        };
+        assert_eq!(config.started_status(), DOING);
+    }
+
+    #[test]
+    fn free_text_keeps_unknown_dashed_words_and_moves_options_first() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            free_text_args(args("backlog add fix the -v flag -p high")),
+            args("backlog add -p high -- fix the -v flag")
+        );
+        assert_eq!(
+            free_text_args(args("backlog --format json edit 3 use --force")),
+            args("backlog --format json edit -- 3 use --force")
+        );
+        assert_eq!(
+            free_text_args(args("backlog add x -- -p y")),
+            args("backlog add -- x -p y")
+        );
+        assert_eq!(
+            free_text_args(args("backlog list -p")),
+            args("backlog list -p")
+        );
+    }
+
+    #[test]
+    fn duplicate_ids_are_refused_on_load_and_renumbered_by_salvage() {
+        let mut backlog = backlog(&["a", "b", "c"]);
+        backlog.items[2].id = 2;
+        let content = serde_json::to_string_pretty(&backlog).unwrap();
+
+        let err = parse_backlog(&content, Path::new("backlog.json"))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("@2"));
+
+        let salvaged = salvage_backlog(&content);
+        let ids: Vec<u64> = salvaged.items.iter().map(|item| item.id).collect();
+        assert_eq!(ids, [1, 2, 4]);
+        assert_eq!(salvaged.next_id, 5);
+        assert!(parse_backlog(&serde_json::to_string(&salvaged).unwrap(), Path::new("x")).is_ok());
+    }
+
+    /// Undoes the change from `before` to `after`, checks that redoing it
+    /// gets `after` back and returns the undone backlog
+    fn undo_and_redo(before: &Backlog, after: &Backlog) -> Backlog {
+        let changes = Changes::between(before, after);
+        assert!(changes.applies_to(after, true));
+        let undone = changes.apply(after, true);
+        assert!(changes.applies_to(&undone, false));
+        let redone = changes.apply(&undone, false);
+        assert_eq!(descriptions(&redone), descriptions(after));
+        assert_eq!(redone.next_id, after.next_id);
+        undone
+    }
+
+    fn temp_backlog_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backlog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("backlog.json")
+    }
+
+    #[test]
+    fn undo_of_an_add_removes_the_item_and_frees_its_id() {
+        let before = backlog(&["a", "b"]);
+        let mut after = before.clone();
+        after.push("c".to_string());
+
+        let undone = undo_and_redo(&before, &after);
+        assert_eq!(descriptions(&undone), ["a", "b"]);
+        assert_eq!(undone.next_id, before.next_id);
+    }
+
+    #[test]
+    fn undo_of_a_remove_brings_back_the_subtasks_in_place() {
+        let mut before = backlog(&["a", "parent", "d"]);
+        before.push_under(1, "child 1".to_string());
+        before.push_under(1, "child 2".to_string());
+        let mut after = before.clone();
+        after.remove_subtree(1);
+        assert_eq!(descriptions(&after), ["a", "d"]);
+
+        let undone = undo_and_redo(&before, &after);
+        assert_eq!(
+            descriptions(&undone),
+            ["a", "parent", "child 1", "child 2", "d"]
+        );
+        let parent = undone.items[1].id;
+        assert_eq!(undone.items[2].parent, Some(parent));
+        assert_eq!(undone.items[3].parent, Some(parent));
+    }
+
+    #[test]
+    fn undo_of_a_move_restores_the_order_of_subtasks_it_carried() {
+        let mut before = backlog(&["a", "parent"]);
+        before.push_under(1, "child".to_string());
+        let mut after = before.clone();
+        after.move_to(1, 0);
+        assert_eq!(descriptions(&after), ["parent", "child", "a"]);
+
+        let undone = undo_and_redo(&before, &after);
+        assert_eq!(descriptions(&undone), ["a", "parent", "child"]);
+    }
+
+    #[test]
+    fn undo_is_refused_once_a_touched_item_was_edited() {
+        let before = backlog(&["a", "b"]);
+        let mut after = before.clone();
+        after.items[1].description = "b, reworded".to_string();
+        let changes = Changes::between(&before, &after);
+
+        // Editing an item the change didn't touch is kept by the undo
+        let mut current = after.clone();
+        current.items[0].description = "a, reworded".to_string();
+        assert!(changes.applies_to(&current, true));
+        let undone = changes.apply(&current, true);
+        assert_eq!(descriptions(&undone), ["a, reworded", "b"]);
+
+        current.items[1].description = "b, reworded again".to_string();
+        assert!(!changes.applies_to(&current, true));
+    }
+
+    #[test]
+    fn undo_of_an_archive_moves_the_items_back_from_the_archive() {
+        let path = temp_backlog_path("undo-archive");
+        let archive_file = archive_path(&path);
+        let mut backlog = backlog(&["done", "b"]);
+        backlog.push_under(0, "done too".to_string());
+        backlog.items[0].set_status(DONE);
+        backlog.items[1].set_status(DONE);
+        let mut archive = Backlog::default();
+        save_backlog(&path, &backlog).unwrap();
+
+        let backlog_before = backlog.clone();
+        let archive_before = archive.clone();
+        let (ids, _) = backlog.completed_subtrees(None);
+        archive
+            .items
+            .extend(backlog.remove_all(&ids).into_iter().flatten());
+        save_backlog(&archive_file, &archive).unwrap();
+        save_backlog(&path, &backlog).unwrap();
+        let changes = Changes::between(&backlog_before, &backlog);
+        let archived = Changes::between(&archive_before, &archive);
+        record_change(&path, "archive", changes, Some(archived)).unwrap();
+        assert_eq!(descriptions(&backlog), ["b"]);
+
+        let (action, undone) = step_history(&path, false, false).unwrap();
+        assert_eq!(action, "archive");
+        assert_eq!(descriptions(&undone), ["done", "done too", "b"]);
+        assert!(load_backlog(&archive_file).unwrap().items.is_empty());
+
+        step_history(&path, true, false).unwrap();
+        let archive = load_backlog(&archive_file).unwrap();
+        assert_eq!(descriptions(&archive), ["done", "done too"]);
+        assert_eq!(descriptions(&load_backlog(&path).unwrap()), ["b"]);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn undo_of_a_renumbered_restore_gives_the_archive_its_id_back() {
+        let path = temp_backlog_path("undo-restore");
+        let archive_file = archive_path(&path);
+        // The archived item's ID was handed out again since
+        let mut archive = backlog(&["archived"]);
+        let mut backlog = backlog(&["new"]);
+        save_backlog(&path, &backlog).unwrap();
+        save_backlog(&archive_file, &archive).unwrap();
+
+        let backlog_before = backlog.clone();
+        let archive_before = archive.clone();
+        let idx = backlog.restore(archive.remove_subtree(0));
+        assert_eq!(backlog.items[idx].id, 2);
+        save_backlog(&path, &backlog).unwrap();
+        save_backlog(&archive_file, &archive).unwrap();
+        let changes = Changes::between(&backlog_before, &backlog);
+        let archived = Changes::between(&archive_before, &archive);
+        record_change(&path, "restore @1", changes, Some(archived)).unwrap();
+
+        let (_, undone) = step_history(&path, false, false).unwrap();
+        assert_eq!(descriptions(&undone), ["new"]);
+        let archive = load_backlog(&archive_file).unwrap();
+        assert_eq!(descriptions(&archive), ["archived"]);
+        assert_eq!(archive.items[0].id, 1);
+
+        let (_, redone) = step_history(&path, true, false).unwrap();
+        assert_eq!(descriptions(&redone), ["new", "archived"]);
+        assert_eq!(redone.items[1].id, 2);
+        assert_eq!(redone.next_id, 3);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn parse_due_accepts_dates_words_weekdays_and_offsets() {
+        // A Wednesday at the end of a month
+        let today = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+        let date = |y, m, d| Ok(NaiveDate::from_ymd_opt(y, m, d).unwrap());
+        assert_eq!(parse_due("2024-03-05", today), date(2024, 3, 5));
+        assert_eq!(parse_due(" Today ", today), date(2024, 1, 31));
+        assert_eq!(parse_due("tomorrow", today), date(2024, 2, 1));
+        assert_eq!(parse_due("+3", today), date(2024, 2, 3));
+        assert_eq!(parse_due("+2w", today), date(2024, 2, 14));
+        assert_eq!(parse_due("+1m", today), date(2024, 2, 29));
+        assert_eq!(parse_due("fri", today), date(2024, 2, 2));
+        // The same weekday means next week's
+        assert_eq!(parse_due("Wednesday", today), date(2024, 2, 7));
+        for invalid in ["", "soon", "+", "+2y", "+-1d", "2024-02-30"] {
+            assert!(parse_due(invalid, today).is_err(), "{invalid}");
         }
     }
+
+    #[test]
+    fn parse_age_counts_back_from_now() {
+        let now: DateTime<Utc> = "2024-03-31T12:00:00Z".parse().unwrap();
+        let at = |s: &str| Ok(s.parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(parse_age("14d", now), at("2024-03-17T12:00:00Z"));
+        assert_eq!(parse_age("3", now), at("2024-03-28T12:00:00Z"));
+        assert_eq!(parse_age("2W", now), at("2024-03-17T12:00:00Z"));
+        assert_eq!(parse_age("1m", now), at("2024-02-29T12:00:00Z"));
+        for invalid in ["", "d", "two weeks", "-1d", "1y"] {
+            assert!(parse_age(invalid, now).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn item_specs_parse_numbers_ids_and_ranges() {
+        assert!(matches!(
+            "3".parse(),
+            Ok(ItemSpec::One(ItemRef::Position(3)))
+        ));
+        assert!(matches!("@12".parse(), Ok(ItemSpec::One(ItemRef::Id(12)))));
+        assert!(matches!("2-4".parse(), Ok(ItemSpec::Range(2, 4))));
+        assert!(matches!("5-5".parse(), Ok(ItemSpec::Range(5, 5))));
+        for invalid in ["4-2", "@1-3", "1-", "x", "@x", "-1"] {
+            assert!(invalid.parse::<ItemSpec>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn select_merges_specs_in_backlog_order() {
+        let mut backlog = backlog(&["a", "b", "c", "d", "e"]);
+        backlog.items.swap(0, 4);
+        let specs =
+            |list: &[&str]| -> Vec<ItemSpec> { list.iter().map(|s| s.parse().unwrap()).collect() };
+        // Overlapping specs select each item once, ordered by position
+        let ids = backlog.select(&specs(&["4", "2-3", "@5", "3"])).unwrap();
+        assert_eq!(ids, [5, 2, 3, 4]);
+
+        let out_of_range = backlog.select(&specs(&["2-6"])).err().unwrap();
+        assert_eq!(out_of_range.code, EXIT_NO_SUCH_ITEM);
+        assert!(backlog.select(&specs(&["0-1"])).is_err());
+        assert!(backlog.select(&specs(&["6"])).is_err());
+        assert!(backlog.select(&specs(&["@9"])).is_err());
+    }
+
+    #[test]
+    fn indent_and_outdent_keep_subtasks_with_their_parent() {
+        let mut backlog = backlog(&["a", "b", "c", "d"]);
+        assert!(!backlog.indent(0));
+        assert!(backlog.indent(1));
+        assert!(backlog.indent(2));
+        // c is now a's second subtask, so indenting again puts it under b
+        assert!(backlog.indent(2));
+        let depths: Vec<usize> = (0..4).map(|i| backlog.depth(i)).collect();
+        assert_eq!(depths, [0, 1, 2, 0]);
+
+        // b takes its subtask along
+        assert!(backlog.outdent(1));
+        assert_eq!(descriptions(&backlog), ["a", "b", "c", "d"]);
+        assert_eq!(backlog.items[1].parent, None);
+        assert_eq!(backlog.items[2].parent, Some(backlog.items[1].id));
+        assert!(!backlog.outdent(3));
+    }
+
+    #[test]
+    fn outdent_places_the_item_after_its_parents_subtree() {
+        let mut backlog = backlog(&["parent", "d"]);
+        backlog.push_under(0, "b".to_string());
+        backlog.push_under(0, "c".to_string());
+        assert!(backlog.outdent(1));
+        assert_eq!(descriptions(&backlog), ["parent", "c", "b", "d"]);
+        assert_eq!(backlog.items[2].parent, None);
+    }
+
+    #[test]
+    fn move_to_carries_subtasks_and_stays_within_the_parent() {
+        let mut backlog = backlog(&["a", "parent", "b"]);
+        backlog.push_under(1, "child 1".to_string());
+        backlog.push_under(1, "child 2".to_string());
+        assert_eq!(backlog.move_to(4, 0), 0);
+        assert_eq!(
+            descriptions(&backlog),
+            ["b", "a", "parent", "child 1", "child 2"]
+        );
+
+        // A subtask only moves among its siblings
+        assert_eq!(backlog.move_to(4, 0), 3);
+        assert_eq!(
+            descriptions(&backlog),
+            ["b", "a", "parent", "child 2", "child 1"]
+        );
+
+        // Moving down past a parent skips its whole subtree
+        assert_eq!(backlog.move_to(0, 4), 4);
+        assert_eq!(
+            descriptions(&backlog),
+            ["a", "parent", "child 2", "child 1", "b"]
+        );
+        // It stops once there are no siblings left to pass
+        assert_eq!(backlog.move_to(1, 4), 2);
+        assert_eq!(
+            descriptions(&backlog),
+            ["a", "b", "parent", "child 2", "child 1"]
+        );
+    }
+
+    #[test]
+    fn add_blocker_refuses_cycles() {
+        let mut backlog = backlog(&["a", "b", "c"]);
+        assert!(backlog.add_blocker(0, 0).is_err());
+        assert!(backlog.add_blocker(0, 1).is_ok());
+        assert!(backlog.add_blocker(1, 2).is_ok());
+        // Adding the same blocker twice keeps a single entry
+        assert!(backlog.add_blocker(0, 1).is_ok());
+        assert_eq!(backlog.items[0].blocked_by, [2]);
+
+        // c -> a would close a -> b -> c
+        let err = backlog.add_blocker(2, 0).unwrap_err();
+        assert!(err.contains("cycle"), "{err}");
+        assert!(backlog.add_blocker(1, 0).is_err());
+        assert!(backlog.items[2].blocked_by.is_empty());
+        assert!(backlog.add_blocker(0, 2).is_ok());
+    }
+
+    #[test]
+    fn read_order_takes_union_merged_lines() {
+        let path = temp_backlog_path("read-order");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let order = order_path(&path);
+        fs::write(
+            &order,
+            "version 5\nnext 4\n3\n1\nnext 6\n<<<<<<< ours\n1\n2\n",
+        )
+        .unwrap();
+        let read = read_order(&order).unwrap();
+        assert_eq!(read.ids, [3, 1, 1, 2]);
+        assert_eq!(read.next_id, 6);
+        assert_eq!(read.version, 5);
+
+        // A missing order file is one from the first version of the layout
+        fs::remove_file(&order).unwrap();
+        let read = read_order(&order).unwrap();
+        assert!(read.ids.is_empty());
+        assert_eq!(read.version, 1);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn read_item_files_follows_the_order_file_and_appends_unlisted_items() {
+        let path = temp_backlog_path("read-item-files");
+        let mut backlog = backlog(&["a", "b", "c"]);
+        backlog.push_under(0, "child".to_string());
+        write_item_files(&path, &backlog).unwrap();
+        let read = read_item_files(&path).unwrap();
+        assert_eq!(descriptions(&read), ["a", "child", "b", "c"]);
+        assert_eq!(read.next_id, backlog.next_id);
+
+        // An item added on another branch has no line in the order file yet,
+        // and a subtask listed before its parent still ends up under it
+        let mut other = Backlog {
+            next_id: 5,
+            ..Backlog::default()
+        };
+        other.push("from a branch".to_string());
+        let item = &other.items[0];
+        fs::write(
+            items_dir(&path).join(item_file_name(item)),
+            serde_json::to_string(item).unwrap(),
+        )
+        .unwrap();
+        let order = format!("version {}\nnext 5\n4\n3\n2\n1\n", BACKLOG_VERSION);
+        fs::write(order_path(&path), order).unwrap();
+        let read = read_item_files(&path).unwrap();
+        assert_eq!(
+            descriptions(&read),
+            ["c", "b", "a", "child", "from a branch"]
+        );
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn markers_need_a_comment_leader() {
+        let pattern = marker_pattern();
+        let find = |line: &str| find_marker(&pattern, line);
+        let marker = |kind: &str, text: &str| Some((kind.to_string(), text.to_string()));
+        assert_eq!(
+            find("    // TODO: handle errors"),
+            marker("todo", "handle errors")
+        );
+        assert_eq!(
+            find("x = 1  # FIXME(ana) off by one"),
+            marker("fixme", "off by one")
+        );
+        assert_eq!(
+            find("/* HACK: skip the cache */"),
+            marker("hack", "skip the cache")
+        );
+        assert_eq!(
+            find(" * TODO document this"),
+            marker("todo", "document this")
+        );
+        assert_eq!(
+            find("-- TODO: add an index"),
+            marker("todo", "add an index")
+        );
+        assert_eq!(find("<!-- TODO: alt text -->"), marker("todo", "alt text"));
+        assert_eq!(find("//! TODO"), marker("todo", ""));
+        assert_eq!(find("let todo = TODO_LIST;"), None);
+        assert_eq!(find("println!(\"TODO: not a comment\");"), None);
+        assert_eq!(find("// todo: lowercase is prose"), None);
+        assert_eq!(find("// TODOS are not markers"), None);
+    }
+
+    #[test]
+    fn reconcile_markers_follows_moved_comments_and_flags_gone_ones() {
+        let marker = |line, text: &str| Marker {
+            file: "src/lib.rs".to_string(),
+            line,
+            kind: "todo".to_string(),
+            text: text.to_string(),
+        };
+        let source = |line, text: &str| Source {
+            file: "src/lib.rs".to_string(),
+            line,
+            text: text.to_string(),
+            missing: false,
+        };
+        let mut archive = backlog(&["archived"]);
+        archive.items[0].source = Some(source(20, "archived"));
+        let mut backlog = backlog(&["moved", "gone", "manual"]);
+        backlog.items[0].source = Some(source(3, "moved"));
+        backlog.items[1].source = Some(source(8, "gone"));
+        let markers = [marker(2, "archived"), marker(5, "moved"), marker(9, "new")];
+
+        let result = reconcile_markers(&mut backlog, &archive, &markers);
+        assert_eq!(result.new, [2]);
+        assert_eq!(result.gone, [backlog.items[1].id]);
+        assert_eq!(backlog.items[0].source.as_ref().unwrap().line, 5);
+        assert!(backlog.items[1].source.as_ref().unwrap().missing);
+
+        // An item already flagged isn't reported again, and one whose
+        // comment comes back loses the flag
+        let markers = [marker(5, "moved"), marker(8, "gone")];
+        let result = reconcile_markers(&mut backlog, &archive, &markers);
+        assert!(result.new.is_empty() && result.gone.is_empty());
+        assert!(!backlog.items[1].source.as_ref().unwrap().missing);
+        let result = reconcile_markers(&mut backlog, &archive, &markers[..1]);
+        assert_eq!(result.gone.len(), 1);
+        let result = reconcile_markers(&mut backlog, &archive, &markers[..1]);
+        assert!(result.gone.is_empty());
+    }
+
+    #[test]
+    fn branch_name_slugs_the_first_words_of_the_description() {
+        let mut backlog = backlog(&[
+            "Fix: the login redirect (again)!",
+            "Make the importer accept exported files from every version we ever shipped",
+            "???",
+        ]);
+        backlog.items[0].id = 12;
+        let names: Vec<String> = backlog
+            .items
+            .iter()
+            .map(|item| branch_name("backlog/", item))
+            .collect();
+        assert_eq!(names[0], "backlog/12-fix-the-login-redirect-again");
+        // Whole words only, up to about 40 characters
+        assert_eq!(
+            names[1],
+            "backlog/2-make-the-importer-accept-exported-files"
+        );
+        assert_eq!(names[2], "backlog/3");
+        assert_eq!(branch_name("", &backlog.items[2]), "3");
+    }
 }